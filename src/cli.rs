@@ -0,0 +1,178 @@
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::util::TaskType;
+
+/// github graphql 数据采集工具
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// log4rs 配置文件路径
+    #[arg(long, global = true, default_value = "config/log4rs.yaml")]
+    pub log_config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// 遍历仓库列表，采集每个仓库的数据。
+    Crawl(CrawlArgs),
+    /// 只重跑失败列表（`<output>/dead_letter.json`）里的任务，忽略仓库列表。
+    RetryFailed(RetryArgs),
+    /// 查看仓库列表中每个仓库每类任务的采集进度。
+    Status(CommonArgs),
+    /// 按消耗从高到低列出每个仓库每类任务的查询消耗（rateLimit 的 cost）。
+    Cost(CommonArgs),
+    /// 把已采集的分页文件合并导出为 JSON Lines，每行一个节点。
+    Export(ExportArgs),
+    /// 校验已采集的分页文件是否完整可解析。
+    Verify(CommonArgs),
+    /// 对单个仓库发起一次查询，把结果打印到标准输出。
+    Query(QueryArgs),
+    /// 把旧布局的输出目录改写为当前布局，续爬状态保持不变。
+    Migrate(MigrateArgs),
+    /// 以子进程运行采集，异常退出时按退避时间重启，代替原来的 `demon.ps1`。
+    Supervise(SuperviseArgs),
+}
+
+/// 各个子命令共用的参数
+#[derive(Debug, Args)]
+pub struct CommonArgs {
+    /// 仓库列表文件，每行一个 `<owner>/<repo>`，`#` 开头的行会被忽略。
+    #[arg(long, default_value = "repolist.txt")]
+    pub repo_list: PathBuf,
+
+    /// 配置文件路径
+    #[arg(long, default_value = "config.yml")]
+    pub config: PathBuf,
+
+    /// 采集结果的根目录
+    #[arg(long, default_value = "output")]
+    pub output: PathBuf,
+
+    /// 需要处理的任务类型，逗号分隔。
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = TaskType::ALL
+    )]
+    pub tasks: Vec<TaskType>,
+
+    /// 每个仓库每个类型的数据采集步数的上限
+    #[arg(long, default_value_t = 5)]
+    pub step_limit: i32,
+}
+
+#[derive(Debug, Args)]
+pub struct CrawlArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// 同时采集的仓库数量，所有 worker 共用 config 中 `governor` 的请求节流。
+    #[arg(long, default_value_t = 1)]
+    pub workers: usize,
+
+    /// 增量同步：已经全量采集完的任务只拉取高水位之后更新过的节点并合并进已有数据，没采完的照常续爬。
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// 只估算每个仓库每类任务的页数、消耗和耗时，不采集也不写入任何数据。
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// 在这个地址上提供 Prometheus 的 `/metrics`，例如 `127.0.0.1:9898`，不填写则不开启。
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+pub struct RetryArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// 同时重试的仓库数量
+    #[arg(long, default_value_t = 1)]
+    pub workers: usize,
+
+    /// 已经全量采集完的任务按增量同步重试，和 `crawl --incremental` 相同。
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// 最多重试几轮，每一轮把失败列表里的任务都跑一遍。
+    #[arg(long, default_value_t = 3)]
+    pub passes: u32,
+
+    /// 第一轮和第二轮之间的等待秒数，之后每轮翻倍。
+    #[arg(long, default_value_t = 600)]
+    pub backoff_secs: u64,
+
+    /// 累计失败达到这个次数的任务不再重试，只保留在失败列表里。
+    #[arg(long, default_value_t = 5)]
+    pub max_attempts: u32,
+
+    /// 在这个地址上提供 Prometheus 的 `/metrics`，和 `crawl --metrics-addr` 相同。
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// 导出文件路径
+    #[arg(long, default_value = "export.jsonl")]
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// 目标仓库，格式为 `<owner>/<repo>`。
+    #[arg(long)]
+    pub repo: String,
+
+    /// 查询的任务类型
+    #[arg(long, value_enum)]
+    pub task: TaskType,
+
+    /// 从哪个 cursor 之后开始查询，不填写则查询第一页。
+    #[arg(long)]
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct MigrateArgs {
+    /// 采集结果的根目录
+    #[arg(long, default_value = "output")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct SuperviseArgs {
+    /// 第一次重启前的等待秒数，连续快速崩溃时每次翻倍。
+    #[arg(long, default_value_t = 5)]
+    pub min_backoff_secs: u64,
+
+    /// 重启等待的上限
+    #[arg(long, default_value_t = 600)]
+    pub max_backoff_secs: u64,
+
+    /// 运行时间短于这个秒数就退出的算作快速崩溃
+    #[arg(long, default_value_t = 60)]
+    pub fast_crash_secs: u64,
+
+    /// 连续快速崩溃这么多次之后不再重启
+    #[arg(long, default_value_t = 5)]
+    pub max_fast_crashes: u32,
+
+    /// 子进程的参数，写在 `--` 之后，例如 `supervise -- crawl --workers 2`。
+    #[arg(last = true, default_values_t = ["crawl".to_string()])]
+    pub command: Vec<String>,
+}
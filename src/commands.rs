@@ -0,0 +1,396 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::cli::{CommonArgs, ExportArgs, QueryArgs};
+use crate::cost::CostTotals;
+use crate::instance_lock;
+use crate::ledger::{RepoLedger, StepRecord, TaskStatus};
+use crate::progress::{CrawlStatus, RunState};
+use crate::query;
+use crate::util::{self, TaskType};
+
+/// 打印仓库列表中每个仓库每类任务的采集进度
+pub fn status(args: &CommonArgs) -> Result<()> {
+    let root = args.output.as_path();
+
+    // 采集进行中也可以查看，只是读到的是最近一次保存的元数据。
+    let holder = instance_lock::holder(root);
+    if let Some(owner) = &holder {
+        println!("# {} 正在被采集（{owner}），以下为只读快照", root.display());
+    }
+
+    if let Some(crawl_status) = CrawlStatus::load(root)? {
+        print_crawl_status(&crawl_status, holder.is_some());
+        println!();
+    }
+
+    for (repo_owner, repo_name) in util::read_repo_list(&args.repo_list)? {
+        let ledger = RepoLedger::load(root, &repo_owner, &repo_name)?;
+
+        for &task_type in &args.tasks {
+            let Some(task) = ledger.task(task_type) else {
+                println!(
+                    "{repo_owner}/{repo_name}\t{task_type}\t{}",
+                    TaskStatus::Pending
+                );
+                continue;
+            };
+
+            let items: usize = task.steps.iter().map(|r| r.item_count).sum();
+            let last = task.last_step().map_or("-".to_string(), |r| {
+                format!("step {}/{} at {}", r.step, args.step_limit, r.fetched_at)
+            });
+
+            print!(
+                "{repo_owner}/{repo_name}\t{task_type}\t{status}\tpages: {pages}\titems: {items}\t{last}",
+                status = task.status,
+                pages = task.steps.len(),
+            );
+            if let Some(mark) = task.high_water_mark {
+                print!("\tsynced to {mark} ({} delta pages)", task.deltas.len());
+            }
+            match &task.last_error {
+                Some(e) => println!("\terror: {e}"),
+                None => println!(),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 距离现在的时间，例如 `in 1h05m`、`12s ago`。
+fn relative(at: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>) -> String {
+    let secs = (at - now).num_seconds();
+    let abs = secs.unsigned_abs();
+    let text = match abs {
+        0..=59 => format!("{abs}s"),
+        60..=3599 => format!("{}m{:02}s", abs / 60, abs % 60),
+        _ => format!("{}h{:02}m", abs / 3600, abs % 3600 / 60),
+    };
+    if secs > 0 {
+        format!("in {text}")
+    } else {
+        format!("{text} ago")
+    }
+}
+
+/// 打印 crawl / retry-failed 写下的 `status.json`
+fn print_crawl_status(status: &CrawlStatus, running: bool) {
+    let now = chrono::Utc::now();
+
+    println!(
+        "# status.json 更新于 {} ({})",
+        status.updated_at,
+        relative(status.updated_at, now)
+    );
+    println!(
+        "{}\t{}\tpid {}\tstarted at {}",
+        status.command, status.state, status.pid, status.started_at
+    );
+    // 进程被杀掉时来不及改写状态，文件会一直停在 running。
+    if status.state == RunState::Running && !running {
+        println!("# 没有实例持有输出目录的锁，上次运行没有正常结束");
+    }
+
+    let repos = &status.repos;
+    print!(
+        "repos: {}/{} done\tfailed: {}\tpending: {}",
+        repos.done, repos.total, repos.failed, repos.pending
+    );
+    match status.eta {
+        Some(eta) => println!("\teta: {eta} ({})", relative(eta, now)),
+        None => println!(),
+    }
+
+    println!(
+        "last hour: {} requests\t{} points",
+        status.last_hour.requests, status.last_hour.points
+    );
+
+    for token in &status.rate_limits {
+        match &token.rate_limit {
+            Some(rate_limit) => {
+                let reset = chrono::DateTime::from_timestamp(rate_limit.reset, 0).unwrap_or(now);
+                println!(
+                    "token #{}\tremaining: {}/{}\treset at {reset} ({})",
+                    token.token,
+                    rate_limit.remaining,
+                    rate_limit.limit,
+                    relative(reset, now)
+                );
+            }
+            None => println!("token #{}\tnot used yet", token.token),
+        }
+    }
+
+    for worker in &status.workers {
+        print!("{}", worker.name);
+        if let (Some(repo), Some(task)) = (&worker.repo, worker.task) {
+            match worker.step {
+                Some(step) => print!("\t{repo}\t{task}\tstep {step}/{}", status.step_limit),
+                None => print!("\t{repo}\t{task}\tstarting"),
+            }
+        }
+        match &worker.backoff {
+            Some(backoff) => println!(
+                "\tbacking off until {} ({}): {}",
+                backoff.until,
+                relative(backoff.until, now),
+                backoff.reason
+            ),
+            None => println!(),
+        }
+    }
+}
+
+/// 按消耗从高到低打印每个仓库每类任务的查询消耗，最后按任务类型汇总。
+pub fn cost(args: &CommonArgs) -> Result<()> {
+    let root = args.output.as_path();
+
+    let mut rows: Vec<(String, TaskType, CostTotals)> = Vec::new();
+    for (repo_owner, repo_name) in util::read_repo_list(&args.repo_list)? {
+        let ledger = RepoLedger::load(root, &repo_owner, &repo_name)?;
+
+        for &task_type in &args.tasks {
+            if let Some(task) = ledger.task(task_type) {
+                rows.push((format!("{repo_owner}/{repo_name}"), task_type, task.cost));
+            }
+        }
+    }
+
+    rows.sort_by_key(|(_, _, cost)| std::cmp::Reverse(cost.points));
+
+    let print_row = |name: &str, task: &str, cost: &CostTotals| {
+        let per_request = if cost.requests > 0 {
+            cost.points as f64 / cost.requests as f64
+        } else {
+            0.0
+        };
+        println!(
+            "{name}\t{task}\tpoints: {}\trequests: {}\tnodes: {}\tpoints/request: {per_request:.1}",
+            cost.points, cost.requests, cost.node_count
+        );
+    };
+
+    for (repo, task_type, cost) in &rows {
+        print_row(repo, &task_type.to_string(), cost);
+    }
+
+    println!();
+
+    let mut total = CostTotals::default();
+    for &task_type in &args.tasks {
+        let mut by_task = CostTotals::default();
+        for (_, _, cost) in rows.iter().filter(|(_, t, _)| *t == task_type) {
+            by_task += *cost;
+        }
+        print_row("*", &task_type.to_string(), &by_task);
+        total += by_task;
+    }
+    print_row("*", "*", &total);
+
+    Ok(())
+}
+
+/// 把分页文件中的节点逐个导出为 JSON Lines
+pub fn export(ExportArgs { common: args, to }: &ExportArgs) -> Result<()> {
+    let root = args.output.as_path();
+
+    if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!("{parent:?} 路径创建出现问题"))?;
+    }
+
+    let mut writer =
+        BufWriter::new(fs::File::create(to).context(format!("{} 导出文件创建失败", to.display()))?);
+
+    let mut node_count = 0;
+
+    for (repo_owner, repo_name) in util::read_repo_list(&args.repo_list)? {
+        let ledger = RepoLedger::load(root, &repo_owner, &repo_name)?;
+
+        for &task_type in &args.tasks {
+            let Some(task) = ledger.task(task_type) else {
+                continue;
+            };
+            let task_path = util::task_dir(root, &repo_owner, &repo_name, task_type);
+
+            // 增量同步的变更页排在后面，同一个 url 的节点以后出现的为准。
+            let mut nodes: Vec<serde_json::Value> = Vec::new();
+            let mut index_by_url: HashMap<String, usize> = HashMap::new();
+            for file in task.data_files() {
+                for node in read_page_nodes(&task_path.join(file), task_type)? {
+                    match node.get("url").and_then(|u| u.as_str()).map(str::to_string) {
+                        Some(url) => match index_by_url.get(&url) {
+                            Some(&i) => nodes[i] = node,
+                            None => {
+                                index_by_url.insert(url, nodes.len());
+                                nodes.push(node);
+                            }
+                        },
+                        None => nodes.push(node),
+                    }
+                }
+            }
+
+            for node in nodes {
+                let line = serde_json::json!({
+                    "repo": format!("{repo_owner}/{repo_name}"),
+                    "task": task_type.to_string(),
+                    "node": node,
+                });
+                serde_json::to_writer(&mut writer, &line)?;
+                writeln!(writer)?;
+                node_count += 1;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    log::info!("共导出 {node_count} 个节点到 {}", to.display());
+
+    Ok(())
+}
+
+/// 按元数据逐页校验分页文件：文件存在、能解析出节点列表、节点数一致，并且 cursor 首尾相接。
+/// 增量同步的变更页只检查前两项。
+pub fn verify(args: &CommonArgs) -> Result<()> {
+    let root = args.output.as_path();
+
+    let mut checked = 0;
+    let mut broken = 0;
+
+    for (repo_owner, repo_name) in util::read_repo_list(&args.repo_list)? {
+        let ledger = RepoLedger::load(root, &repo_owner, &repo_name)?;
+
+        for &task_type in &args.tasks {
+            let Some(task) = ledger.task(task_type) else {
+                continue;
+            };
+            let task_path = util::task_dir(root, &repo_owner, &repo_name, task_type);
+
+            let mut prev: Option<&StepRecord> = None;
+            for record in &task.steps {
+                checked += 1;
+
+                let page = task_path.join(&record.file);
+                let problem = match read_page_nodes(&page, task_type) {
+                    Err(e) => Some(format!("{e:#}")),
+                    Ok(nodes) if nodes.len() != record.item_count => Some(format!(
+                        "节点数 {} 与元数据记录的 {} 不一致",
+                        nodes.len(),
+                        record.item_count
+                    )),
+                    Ok(_) => match prev {
+                        Some(prev) if prev.step + 1 != record.step => Some(format!(
+                            "step {} 之后缺少 step {}",
+                            prev.step,
+                            prev.step + 1
+                        )),
+                        Some(prev)
+                            if prev.end_cursor.is_some() && prev.end_cursor != record.cursor =>
+                        {
+                            Some("cursor 与上一页的 endCursor 不一致".to_string())
+                        }
+                        _ => None,
+                    },
+                };
+
+                if let Some(problem) = problem {
+                    broken += 1;
+                    println!("{}\t{problem}", page.display());
+                }
+
+                prev = Some(record);
+            }
+
+            for delta in &task.deltas {
+                checked += 1;
+
+                let page = task_path.join(&delta.file);
+                let problem = match read_page_nodes(&page, task_type) {
+                    Err(e) => Some(format!("{e:#}")),
+                    Ok(nodes) if nodes.len() != delta.item_count => Some(format!(
+                        "节点数 {} 与元数据记录的 {} 不一致",
+                        nodes.len(),
+                        delta.item_count
+                    )),
+                    Ok(_) => None,
+                };
+
+                if let Some(problem) = problem {
+                    broken += 1;
+                    println!("{}\t{problem}", page.display());
+                }
+            }
+        }
+    }
+
+    println!("checked: {checked}, broken: {broken}");
+
+    if broken > 0 {
+        bail!("{broken} 个分页文件校验失败");
+    }
+
+    Ok(())
+}
+
+/// 对单个仓库发起一次查询
+pub fn query(
+    QueryArgs {
+        common: args,
+        repo,
+        task,
+        cursor,
+    }: &QueryArgs,
+) -> Result<()> {
+    let (repo_owner, repo_name) = repo
+        .split_once('/')
+        .context(format!("{repo} 不是 `<owner>/<repo>` 格式"))?;
+
+    let client = crate::build_client(&args.config)?;
+
+    query::for_task_type!(*task, T => {
+        let result = query::single_query::<T>(
+            repo_owner,
+            repo_name,
+            cursor,
+            query::QueryOrder::CreatedAsc,
+            &mut Default::default(),
+            &client,
+        )?;
+
+        log::info!(
+            "has_next_page: {}, query_cursor: {:?}",
+            result.has_next_page,
+            result.query_cursor
+        );
+
+        println!("{}", serde_json::to_string(&result.response_data)?);
+    });
+
+    Ok(())
+}
+
+/// 读取分页文件，取出 `repository.<connection>.nodes`
+fn read_page_nodes(page: &Path, task_type: TaskType) -> Result<Vec<serde_json::Value>> {
+    let data: serde_json::Value = serde_json::from_reader(
+        fs::File::open(page).context(format!("{} 打开失败", page.display()))?,
+    )
+    .context("JSON 解析失败")?;
+
+    match data
+        .pointer(&format!(
+            "/repository/{}/nodes",
+            task_type.connection_name()
+        ))
+        .cloned()
+    {
+        Some(serde_json::Value::Array(nodes)) => Ok(nodes),
+        _ => bail!("缺少 repository.{}.nodes", task_type.connection_name()),
+    }
+}
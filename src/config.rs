@@ -1,13 +1,68 @@
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, Context, Ok, Result};
+use std::path::{Path, PathBuf};
+
+use crate::governor::GovernorConfig;
+use crate::retry::RetryPolicy;
+use crate::shared_limits;
+use crate::token_pool::Reserve;
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub(crate) struct Config {
-    pub token: String,
+    /// 单个 token 的旧写法，和 `tokens` 合并使用。
+    #[serde(default)]
+    pub token: Option<String>,
+    /// 多个 PAT，请求时选择剩余额度最多的一个。
+    #[serde(default)]
+    pub tokens: Vec<String>,
     pub user_agent: String,
+    /// 所有 worker 共享的请求节流
+    #[serde(default)]
+    pub governor: GovernorConfig,
+    /// 请求失败时的重试策略
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// 每个 token 留给其他工具的额度，剩余额度降到这里就暂停到重置。
+    #[serde(default)]
+    pub reserve: Reserve,
+    /// 本机多个进程共享 token 额度的状态文件，默认放在系统临时目录。
+    #[serde(default = "shared_limits::default_path")]
+    pub shared_state: PathBuf,
+}
+
+impl Config {
+    /// 合并 `token` 和 `tokens`，去掉重复的。
+    pub fn all_tokens(&self) -> Vec<String> {
+        let mut all = Vec::new();
+        for token in self.token.iter().chain(&self.tokens) {
+            if !all.contains(token) {
+                all.push(token.clone());
+            }
+        }
+        all
+    }
 }
 
-pub(crate) fn load() -> Result<Config> {
+pub(crate) fn load(path: &Path) -> Result<Config> {
+    let path_str = path.display();
+    let config: Config = Ok(())
+        .and_then(|_| std::fs::File::open(path).context(format!("{path_str} 没有找到")))
+        .and_then(|f| serde_yaml::from_reader(f).context(format!("{path_str} 解析错误")))?;
+
+    if config.all_tokens().is_empty() {
+        bail!("{path_str} 中至少需要配置一个 token");
+    }
+
+    Ok(config)
+}
+
+#[test]
+fn test_merge_tokens() -> Result<()> {
+    let config: Config =
+        serde_yaml::from_str("token: a\ntokens:\n  - b\n  - a\n  - c\nuser_agent: test\n")?;
+    assert_eq!(config.all_tokens(), vec!["a", "b", "c"]);
+
+    let legacy: Config = serde_yaml::from_str("token: a\nuser_agent: test\n")?;
+    assert_eq!(legacy.all_tokens(), vec!["a"]);
+
     Ok(())
-        .and_then(|_| std::fs::File::open("config.yml").context("config.yml 没有找到"))
-        .and_then(|f| serde_yaml::from_reader(f).context("config.yml 解析错误"))
 }
@@ -0,0 +1,91 @@
+// 查询复杂度的统计。
+//
+// 每个请求拉满 100 个节点时，门槛在计算复杂度而不是 5000 次的请求数，所以每个查询都带上
+// `rateLimit { cost limit remaining resetAt nodeCount }`，记录每个请求、每个仓库、每类任务的消耗。
+// 参考 https://docs.github.com/en/graphql/overview/rate-limits-and-node-limits-for-the-graphql-api#calculating-a-rate-limit-score-before-running-the-call
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::AddAssign;
+
+/// 查询里 `rateLimit` 字段返回的本次查询的消耗
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryCost {
+    pub cost: i64,
+    pub limit: i64,
+    pub remaining: i64,
+    pub reset_at: DateTime<Utc>,
+    pub node_count: i64,
+}
+
+impl QueryCost {
+    /// 从响应体里单独取出 `data.rateLimit`，和具体的查询类型无关。
+    pub fn from_response(text: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Probe {
+            data: Option<ProbeData>,
+        }
+
+        #[derive(Deserialize)]
+        struct ProbeData {
+            #[serde(rename = "rateLimit")]
+            rate_limit: Option<QueryCost>,
+        }
+
+        serde_json::from_str::<Probe>(text).ok()?.data?.rate_limit
+    }
+}
+
+/// 累计的消耗
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostTotals {
+    pub requests: u64,
+    /// rateLimit 的 cost 之和，每小时 5000 分。
+    pub points: i64,
+    pub node_count: i64,
+}
+
+impl CostTotals {
+    pub fn add(&mut self, cost: &QueryCost) {
+        self.requests += 1;
+        self.points += cost.cost;
+        self.node_count += cost.node_count;
+    }
+}
+
+impl AddAssign for CostTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.points += other.points;
+        self.node_count += other.node_count;
+    }
+}
+
+#[test]
+fn test_cost_from_response() {
+    let text = r#"{"data":{"rateLimit":{"cost":3,"limit":5000,"remaining":4990,
+        "resetAt":"2024-01-01T01:00:00Z","nodeCount":5100},"repository":null}}"#;
+
+    let cost = QueryCost::from_response(text).unwrap();
+    assert_eq!(
+        (cost.cost, cost.remaining, cost.node_count),
+        (3, 4990, 5100)
+    );
+
+    let mut totals = CostTotals::default();
+    totals.add(&cost);
+    totals += totals;
+    assert_eq!(
+        totals,
+        CostTotals {
+            requests: 2,
+            points: 6,
+            node_count: 10200,
+        }
+    );
+
+    // 没有 rateLimit 的响应不影响正常解析
+    assert!(QueryCost::from_response(r#"{"data":{"repository":null}}"#).is_none());
+    assert!(QueryCost::from_response("not json").is_none());
+}
@@ -0,0 +1,132 @@
+// 采集失败的仓库/任务记录在 `<root>/dead_letter.json`，`retry-failed` 只重跑这里面的任务。
+// 任务之后采集成功（或者仓库被确认无法访问而跳过）时会从这里移除。
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::util::{self, TaskType};
+
+const DEAD_LETTER_FILE_NAME: &str = "dead_letter.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub repo_owner: String,
+    pub repo_name: String,
+    pub task: TaskType,
+    pub last_error: String,
+    /// 累计失败次数
+    pub attempts: u32,
+    pub first_failed_at: DateTime<Utc>,
+    pub last_failed_at: DateTime<Utc>,
+}
+
+/// 所有 worker 共享的失败列表，每次变更都立即写回文件。
+pub struct DeadLetterQueue {
+    path: PathBuf,
+    // key 为 `<owner>/<repo>/<task>`，写出来的文件按仓库排序，方便人工查看。
+    entries: Mutex<BTreeMap<String, DeadLetter>>,
+}
+
+fn key(repo_owner: &str, repo_name: &str, task: TaskType) -> String {
+    format!("{repo_owner}/{repo_name}/{task}")
+}
+
+impl DeadLetterQueue {
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(DEAD_LETTER_FILE_NAME);
+
+        let entries = if path.exists() {
+            let file = fs::File::open(&path).context(format!("{path:?} 打开失败"))?;
+            serde_json::from_reader(file).context(format!("{path:?} 解析失败"))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn save(&self, entries: &BTreeMap<String, DeadLetter>) -> Result<()> {
+        util::write_atomic(
+            &self.path,
+            serde_json::to_string_pretty(entries)?.as_bytes(),
+        )
+        .context(format!("{:?} 写入失败", self.path))
+    }
+
+    /// 记录一次失败，已经在列表里的累加失败次数。
+    pub fn record_failure(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        task: TaskType,
+        error: String,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        entries
+            .entry(key(repo_owner, repo_name, task))
+            .and_modify(|entry| {
+                entry.attempts += 1;
+                entry.last_error = error.clone();
+                entry.last_failed_at = now;
+            })
+            .or_insert_with(|| DeadLetter {
+                repo_owner: repo_owner.to_string(),
+                repo_name: repo_name.to_string(),
+                task,
+                last_error: error,
+                attempts: 1,
+                first_failed_at: now,
+                last_failed_at: now,
+            });
+
+        self.save(&entries)
+    }
+
+    /// 任务已经不需要重试，不在列表里时什么也不做。
+    pub fn resolve(&self, repo_owner: &str, repo_name: &str, task: TaskType) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.remove(&key(repo_owner, repo_name, task)).is_some() {
+            self.save(&entries)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<DeadLetter> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[test]
+fn test_record_and_resolve() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dead_letter_test_{}", std::process::id()));
+    fs::create_dir_all(&root)?;
+
+    let queue = DeadLetterQueue::load(&root)?;
+    queue.record_failure("o", "r", TaskType::ClosedIssues, "502".to_string())?;
+    queue.record_failure("o", "r", TaskType::ClosedIssues, "504".to_string())?;
+    queue.record_failure("o", "r", TaskType::PRCommits, "502".to_string())?;
+    queue.resolve("o", "r", TaskType::PRCommits)?;
+
+    // 重新读取文件，确认每次变更都写回去了
+    let entries = DeadLetterQueue::load(&root)?.entries();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].task, TaskType::ClosedIssues);
+    assert_eq!(entries[0].attempts, 2);
+    assert_eq!(entries[0].last_error, "504");
+
+    fs::remove_dir_all(&root)?;
+
+    Ok(())
+}
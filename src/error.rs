@@ -0,0 +1,125 @@
+// 请求链路上的错误。anyhow 只在最外层使用，这里按类别区分，
+// 方便主循环决定是记录失败继续下一个仓库，还是中止整次运行。
+
+use reqwest::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// 连接失败、超时、代理中断等，重试之后仍然失败。
+    #[error("网络错误：{0}")]
+    Network(#[from] reqwest::Error),
+
+    /// 重试之后仍然是非 200 的响应码
+    #[error("响应码 {status}：{body}")]
+    HttpStatus { status: StatusCode, body: String },
+
+    /// 重试之后额度仍然没有恢复
+    #[error("速率限制，额度在 {reset:?} 之前不会恢复")]
+    RateLimited { reset: Option<i64> },
+
+    /// GraphQL 响应里的错误，见 `graphql_client_ext::GraphqlError`。
+    #[error("GraphQL 请求失败：{0}")]
+    Graphql(String),
+
+    /// 仓库被改名、删除、设为私有或者被下架
+    #[error("仓库无法访问：{0}")]
+    RepoUnavailable(String),
+
+    #[error("响应解析失败：{0}")]
+    Decode(#[from] serde_json::Error),
+
+    #[error("读写文件失败：{0}")]
+    Io(#[from] std::io::Error),
+
+    /// config 的值不合理，比如预留额度不小于 token 的限额。
+    #[error("配置错误：{0}")]
+    Config(String),
+
+    /// 收到退出信号，进度已经保存，不算失败。
+    #[error("收到退出信号")]
+    Interrupted,
+}
+
+impl Error {
+    /// 换一个仓库也不会好转的错误，遇到时中止整次运行。
+    ///
+    /// 写不了输出目录，或者所有 token 都已失效（只剩一个 token 时收到 401）。
+    /// 收到退出信号时同样要停下所有 worker。
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Config(_) | Error::Interrupted => true,
+            Error::HttpStatus { status, .. } => *status == StatusCode::UNAUTHORIZED,
+            _ => false,
+        }
+    }
+
+    /// 错误类别的简短名字，用作指标的标签。
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Network(_) => "network",
+            Error::HttpStatus { .. } => "http_status",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::Graphql(_) => "graphql",
+            Error::RepoUnavailable(_) => "repo_unavailable",
+            Error::Decode(_) => "decode",
+            Error::Io(_) => "io",
+            Error::Config(_) => "config",
+            Error::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// 一批仓库跑完了，但是有仓库失败，已经记进失败列表。
+///
+/// 和出错退出区分开，用 `FAILURES_EXIT_CODE` 退出，`supervise` 据此不再重启：
+/// 重启只会把同样的仓库再失败一遍，应该用 `retry-failed` 处理。
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct FinishedWithFailures(pub String);
+
+/// 跑完但有失败时的退出码。2 是 clap 参数错误的退出码，这里用 3。
+pub const FAILURES_EXIT_CODE: i32 = 3;
+
+/// 主循环拿到的是 anyhow::Error，写输出文件时的 IO 错误没有经过 `Error`，一样视为致命。
+pub fn is_fatal(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Error>().is_some_and(Error::is_fatal)
+        || e.downcast_ref::<std::io::Error>().is_some()
+}
+
+/// 是否是收到退出信号之后主动停下的
+pub fn is_interrupted(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::Interrupted))
+}
+
+#[test]
+fn test_fatal_errors() {
+    let unauthorized = Error::HttpStatus {
+        status: StatusCode::UNAUTHORIZED,
+        body: "Bad credentials".to_string(),
+    };
+    assert!(unauthorized.is_fatal());
+    assert!(Error::Io(std::io::Error::other("disk full")).is_fatal());
+    assert!(Error::Config("reserve".to_string()).is_fatal());
+
+    assert!(!Error::RepoUnavailable("NOT_FOUND".to_string()).is_fatal());
+    assert!(!Error::HttpStatus {
+        status: StatusCode::BAD_GATEWAY,
+        body: String::new(),
+    }
+    .is_fatal());
+
+    // 主循环拿到的是 anyhow::Error，需要能还原出原来的类别
+    let wrapped = anyhow::Error::from(Error::Graphql("x".to_string())).context("采集失败");
+    assert!(matches!(
+        wrapped.downcast_ref::<Error>(),
+        Some(Error::Graphql(_))
+    ));
+    assert!(!is_fatal(&wrapped));
+
+    let write_failed = anyhow::Error::from(std::io::Error::other("disk full")).context("写入失败");
+    assert!(is_fatal(&write_failed));
+    assert!(!is_interrupted(&write_failed));
+
+    let interrupted = anyhow::Error::from(Error::Interrupted).context("采集中断");
+    assert!(is_fatal(&interrupted) && is_interrupted(&interrupted));
+}
@@ -0,0 +1,229 @@
+// `crawl --dry-run`：开始一次大规模采集之前估算要花多少额度和时间，不写入任何数据。
+//
+// 每个仓库用一次很便宜的查询取回各类任务的 totalCount；每类任务一页的消耗用
+// `rateLimit(dryRun: true)` 计算（只计算不执行，不占用额度），两者相乘就是估算的消耗。
+// 超过 50 条的评论和 commit 需要额外的补齐请求，这部分没法提前知道，不在估算之内。
+
+use anyhow::{Context, Result};
+use graphql_client::GraphQLQuery;
+use std::collections::HashMap;
+
+use crate::cli::CommonArgs;
+use crate::cost::QueryCost;
+use crate::error::Error;
+use crate::graphql_client_ext::{self, GithubClient, Window};
+use crate::query::{self, PaginatedTask, QueryOrder, GITHUB_GRAPHQL_URL};
+use crate::token_pool::Reserve;
+use crate::util::{self, RateLimit, TaskType};
+use crate::window::MAX_WINDOW;
+
+#[allow(clippy::upper_case_acronyms)]
+type DateTime = String;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.docs.graphql",
+    query_path = "get_repo_totals.graphql",
+    response_derives = "Debug"
+)]
+pub struct GetRepoTotals;
+
+// 这个查询没有分页，窗口大小没有意义，502/504 时也只能原样重试。
+impl Window for get_repo_totals::Variables {
+    fn get_window(&self) -> i64 {
+        1
+    }
+
+    fn set_window(&mut self, _window: i64) {}
+}
+
+/// 返回各类任务的节点总数和这次查询实际的消耗，仓库无法访问时总数为 None。
+fn repo_totals(
+    repo_owner: &str,
+    repo_name: &str,
+    client: &GithubClient,
+) -> Result<(Option<get_repo_totals::GetRepoTotalsRepository>, i64)> {
+    let variables = get_repo_totals::Variables {
+        repo_owner: repo_owner.into(),
+        repo_name: repo_name.into(),
+    };
+
+    let posted = match graphql_client_ext::post_graphql_blocking::<GetRepoTotals, _>(
+        client,
+        GITHUB_GRAPHQL_URL,
+        variables,
+    ) {
+        Ok(posted) => posted,
+        // 和采集时一样跳过这个仓库，不中断整个估算。
+        Err(Error::RepoUnavailable(reason)) => {
+            log::warn!("{repo_owner}/{repo_name} 无法访问：{reason}");
+            return Ok((None, 0));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // 响应里没有 rateLimit 时按最低的 1 分计算
+    let cost = posted.cost.as_ref().map_or(1, |cost| cost.cost);
+
+    let errors = posted.response.error_summary();
+    let repository = posted.response.data.and_then(|data| data.repository);
+    if repository.is_none() {
+        log::warn!("{repo_owner}/{repo_name} 无法访问：{errors}");
+    }
+    Ok((repository, cost))
+}
+
+/// 用 dryRun 计算一类任务一整页的消耗
+fn page_cost<T: PaginatedTask>(
+    repo_owner: &str,
+    repo_name: &str,
+    client: &GithubClient,
+) -> Result<QueryCost> {
+    let mut variables = T::build_variables(
+        repo_owner,
+        repo_name,
+        None,
+        MAX_WINDOW,
+        QueryOrder::CreatedAsc,
+    );
+    T::set_dry_run(&mut variables);
+
+    graphql_client_ext::post_graphql_blocking::<T, _>(client, GITHUB_GRAPHQL_URL, variables)?
+        .cost
+        .context("dryRun 的响应里没有 rateLimit")
+}
+
+/// 估算的页数和消耗
+fn estimate(total: i64, page_cost: i64) -> (i64, i64) {
+    let pages = (total.max(0) + MAX_WINDOW - 1) / MAX_WINDOW;
+    (pages, pages * page_cost)
+}
+
+/// 用掉 `points` 分大约需要的小时数。
+///
+/// 第一个窗口按每个 token 当前的剩余额度和重置时间计算，governor 会把剩余额度平摊到重置之前；
+/// 之后每小时按所有 token 的满额计算，两者都扣除预留额度。还没用过或者已经过了重置时间的
+/// token 按满额、一小时之后重置计算。
+fn estimate_hours(
+    points: i64,
+    rate_limits: &[Option<RateLimit>],
+    reserve: Reserve,
+    now: i64,
+) -> f64 {
+    let (mut first_window, mut hourly, mut first_window_end) = (0, 0, now);
+    for rate_limit in rate_limits {
+        let (limit, remaining, reset) = match rate_limit {
+            Some(r) if r.reset > now => (r.limit, r.remaining, r.reset),
+            Some(r) => (r.limit, r.limit, now + 3600),
+            None => {
+                let limit = RateLimit::default().limit;
+                (limit, limit, now + 3600)
+            }
+        };
+        first_window += (remaining - reserve.points(limit)).max(0);
+        hourly += (limit - reserve.points(limit)).max(0);
+        first_window_end = first_window_end.max(reset);
+    }
+
+    let first_window_hours = (first_window_end - now) as f64 / 3600.0;
+    if points <= first_window {
+        return first_window_hours * points as f64 / first_window.max(1) as f64;
+    }
+    first_window_hours + (points - first_window) as f64 / hourly.max(1) as f64
+}
+
+pub fn run(args: &CommonArgs) -> Result<()> {
+    let client = crate::build_client(&args.config)?;
+
+    // 单页消耗只和查询的形状有关，每类任务算一次就够了。
+    let mut page_costs: HashMap<TaskType, i64> = HashMap::new();
+
+    let (mut all_pages, mut all_points, mut lookup_points) = (0, 0, 0);
+
+    for (repo_owner, repo_name) in util::read_repo_list(&args.repo_list)? {
+        let (totals, lookup_cost) = repo_totals(&repo_owner, &repo_name, &client)?;
+        lookup_points += lookup_cost;
+        let Some(totals) = totals else {
+            println!("{repo_owner}/{repo_name}\tunavailable");
+            continue;
+        };
+
+        for &task_type in &args.tasks {
+            let page_cost = match page_costs.get(&task_type) {
+                Some(cost) => *cost,
+                None => {
+                    let cost = query::for_task_type!(task_type, T => {
+                        page_cost::<T>(&repo_owner, &repo_name, &client)
+                    })?;
+                    page_costs.insert(task_type, cost.cost);
+                    cost.cost
+                }
+            };
+
+            let total = query::for_task_type!(task_type, T => T::total_count(&totals));
+            let (pages, points) = estimate(total, page_cost);
+            all_pages += pages;
+            all_points += points;
+
+            println!(
+                "{repo_owner}/{repo_name}\t{task_type}\ttotal: {total}\tpages: {pages}\tpoints: {points}"
+            );
+        }
+    }
+
+    // 估算用过的 token 已经从响应头里拿到了当前的剩余额度和重置时间
+    let rate_limits: Vec<_> = client
+        .tokens
+        .snapshot()
+        .into_iter()
+        .map(|(_, rate_limit)| rate_limit)
+        .collect();
+    let hours = estimate_hours(
+        all_points + lookup_points,
+        &rate_limits,
+        client.tokens.reserve(),
+        chrono::Utc::now().timestamp(),
+    );
+
+    println!();
+    println!(
+        "合计 {all_pages} 页，约 {all_points} 分（另有 totalCount 查询 {lookup_points} 分），\
+         按 {} 个 token 当前的剩余额度和之后每小时的满额计算约需 {hours:.1} 小时",
+        client.tokens.len()
+    );
+    println!("单页消耗：{page_costs:?}，内层连接的补齐请求不在估算之内");
+
+    Ok(())
+}
+
+#[test]
+fn test_estimate_pages_and_points() {
+    assert_eq!(estimate(0, 3), (0, 0));
+    assert_eq!(estimate(1, 3), (1, 3));
+    assert_eq!(estimate(100, 3), (1, 3));
+    assert_eq!(estimate(101, 3), (2, 6));
+    assert_eq!(estimate(25_000, 2), (250, 500));
+}
+
+#[test]
+fn test_estimate_hours_from_current_remaining() {
+    let now = 1_700_000_000;
+    let half_used = Some(RateLimit::new(5000, 1000, 4000, now + 1800));
+
+    // 当前窗口内用得完，按剩余额度平摊到重置之前。
+    assert_eq!(estimate_hours(500, &[half_used], Reserve::None, now), 0.25);
+    // 用完当前剩余的 1000 分之后，每小时 5000 分。
+    assert_eq!(estimate_hours(6000, &[half_used], Reserve::None, now), 1.5);
+    // 预留额度从当前剩余和每小时的满额里都扣除
+    assert_eq!(
+        estimate_hours(5000, &[half_used], Reserve::Points(500), now),
+        0.5 + 4500.0 / 4500.0
+    );
+    // 没用过的 token 按满额、一小时之后重置计算
+    assert_eq!(
+        estimate_hours(15_000, &[None, None], Reserve::None, now),
+        1.5
+    );
+    let expired = Some(RateLimit::new(5000, 0, 5000, now - 1));
+    assert_eq!(estimate_hours(5000, &[expired], Reserve::None, now), 1.0);
+}
@@ -0,0 +1,212 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// 全局请求节流的配置。
+///
+/// 参考 https://docs.github.com/en/rest/using-the-rest-api/best-practices-for-using-the-rest-api#avoid-concurrent-requests
+/// github 建议尽量串行请求，并发请求很容易触发次要速率限制（Secondary Rate Limit）。
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct GovernorConfig {
+    /// 同时在途的请求数上限，和 worker 数量无关。
+    pub max_in_flight: usize,
+    /// 相邻两个请求发出的最小间隔，单位毫秒。
+    pub min_interval_ms: u64,
+    /// 令牌桶的容量（查询分数），也就是允许连续发出的请求的消耗之和。
+    pub burst_points: f64,
+}
+
+impl Default for GovernorConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 2,
+            min_interval_ms: 1_000,
+            burst_points: 200.0,
+        }
+    }
+}
+
+/// 桶里的分数不够时单次最多等待多久，之后按最新的补充速度重新计算。
+const MAX_WAIT: Duration = Duration::from_secs(5);
+
+struct GovernorState {
+    in_flight: usize,
+    next_start: Instant,
+    /// 令牌桶里的分数，实际消耗比预计的多时可以是负数。
+    points: f64,
+    refilled_at: Instant,
+    /// 预计一个请求的消耗，取实际消耗的滑动平均。
+    expected_cost: f64,
+}
+
+impl GovernorState {
+    fn refill(&mut self, now: Instant, per_sec: f64, capacity: f64) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.points = (self.points + elapsed * per_sec.max(0.0)).min(capacity);
+        self.refilled_at = now;
+    }
+}
+
+/// 所有 worker 共享的请求节流器，每个请求发出前都要先拿到一个许可。
+///
+/// 除了在途请求数和请求间隔，还用一个按查询分数计算的令牌桶控制速度：
+/// 桶按剩余额度平摊到重置之前的速度补充，每个请求按 `rateLimit.cost` 扣除。
+/// 这样额度会均匀地用在整个小时里，而不是一口气用完再长时间等待重置。
+pub struct Governor {
+    config: GovernorConfig,
+    state: Mutex<GovernorState>,
+    cond: Condvar,
+}
+
+/// 许可在请求结束时释放
+pub struct Permit<'a> {
+    governor: &'a Governor,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.governor.state.lock().unwrap().in_flight -= 1;
+        self.governor.cond.notify_one();
+    }
+}
+
+impl Governor {
+    pub fn new(config: GovernorConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(GovernorState {
+                in_flight: 0,
+                next_start: now,
+                points: config.burst_points,
+                refilled_at: now,
+                expected_cost: 1.0,
+            }),
+            config,
+            cond: Condvar::new(),
+        }
+    }
+
+    /// 等待直到在途请求数、请求间隔和令牌桶都满足要求。
+    ///
+    /// `refill_per_sec` 是当前每秒补充的分数，每次检查时都重新取一次，
+    /// 因为等待期间可能有其他 worker 拿到了新的额度信息，或者到了重置时间。
+    pub fn acquire(&self, refill_per_sec: impl Fn() -> f64) -> Permit<'_> {
+        let max_in_flight = self.config.max_in_flight.max(1);
+        let min_interval = Duration::from_millis(self.config.min_interval_ms);
+        let capacity = self.config.burst_points.max(1.0);
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.in_flight >= max_in_flight {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+
+            let now = Instant::now();
+            if now < state.next_start {
+                let wait = state.next_start - now;
+                state = self.cond.wait_timeout(state, wait).unwrap().0;
+                continue;
+            }
+
+            let per_sec = refill_per_sec();
+            state.refill(now, per_sec, capacity);
+
+            // 单个请求比桶还大时，等到桶满就放行。
+            let need = state.expected_cost.min(capacity);
+            if state.points < need {
+                let wait = if per_sec > 0.0 {
+                    Duration::from_secs_f64((need - state.points) / per_sec).min(MAX_WAIT)
+                } else {
+                    MAX_WAIT
+                };
+                log::debug!(
+                    "令牌桶剩余 {:.1} 分，预计本次消耗 {need:.1} 分，每秒补充 {per_sec:.2} 分，等待 {wait:?}",
+                    state.points
+                );
+                state = self.cond.wait_timeout(state, wait).unwrap().0;
+                continue;
+            }
+
+            state.in_flight += 1;
+            state.next_start = now + min_interval;
+            return Permit { governor: self };
+        }
+    }
+
+    /// 按查询实际的消耗扣除分数，同时更新对下一个请求消耗的估计。
+    pub fn charge(&self, cost: i64) {
+        let cost = cost.max(0) as f64;
+        let mut state = self.state.lock().unwrap();
+        state.points -= cost;
+        state.expected_cost = state.expected_cost * 0.8 + cost * 0.2;
+    }
+}
+
+#[test]
+fn test_governor_limits_in_flight() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let governor = Governor::new(GovernorConfig {
+        max_in_flight: 2,
+        min_interval_ms: 0,
+        ..Default::default()
+    });
+    let current = AtomicUsize::new(0);
+    let peak = AtomicUsize::new(0);
+
+    std::thread::scope(|s| {
+        for _ in 0..6 {
+            s.spawn(|| {
+                let _permit = governor.acquire(|| 1.0);
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                current.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_governor_spaces_requests() {
+    let governor = Governor::new(GovernorConfig {
+        max_in_flight: 4,
+        min_interval_ms: 30,
+        ..Default::default()
+    });
+
+    let begin = Instant::now();
+    for _ in 0..3 {
+        drop(governor.acquire(|| 1.0));
+    }
+
+    // 第一个请求不用等，后面两个各等一个间隔
+    assert!(begin.elapsed() >= Duration::from_millis(60));
+}
+
+#[test]
+fn test_governor_paces_by_points() {
+    let governor = Governor::new(GovernorConfig {
+        max_in_flight: 1,
+        min_interval_ms: 0,
+        burst_points: 10.0,
+    });
+
+    // 桶是满的，第一个请求不用等
+    let begin = Instant::now();
+    drop(governor.acquire(|| 100.0));
+    assert!(begin.elapsed() < Duration::from_millis(20));
+
+    // 一次用掉 15 分，桶里变成 -5，预计消耗变成 3.8 分，每秒补充 100 分时大约要等 88ms。
+    governor.charge(15);
+    let begin = Instant::now();
+    drop(governor.acquire(|| 100.0));
+    let waited = begin.elapsed();
+    assert!(waited >= Duration::from_millis(80), "{waited:?}");
+    assert!(waited < Duration::from_millis(500), "{waited:?}");
+}
@@ -1,30 +1,315 @@
 use graphql_client::GraphQLQuery;
 use log::warn;
 use std::io::Write;
-use std::{thread, time::Duration};
+use std::time::Duration;
+
+use crate::cost::QueryCost;
+use crate::error::Error;
+use crate::governor::Governor;
+use crate::metrics::{self, Metric};
+use crate::progress::Activity;
+use crate::retry::{RetryPolicy, StatusAction};
+use crate::schedule::ResetScheduler;
+use crate::shared_limits::SharedRateLimits;
+use crate::shutdown;
+use crate::token_pool::TokenPool;
+use crate::util::RateLimit;
 
 pub trait Window {
     fn get_window(&self) -> i64;
     fn set_window(&mut self, window: i64);
 }
 
+/// 替代 `graphql_client::Response`，那边的 `Error` 会丢掉 github 放在顶层的 `type` 字段。
+#[derive(Debug, serde::Deserialize)]
+pub struct GraphqlResponse<Data> {
+    pub data: Option<Data>,
+    #[serde(default)]
+    pub errors: Vec<GraphqlError>,
+}
+
+/// github 的 GraphQL 错误，例如：
+///
+/// `{"type":"NOT_FOUND","path":["repository"],"message":"Could not resolve to a Repository with the name 'o/r'."}`
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct GraphqlError {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub message: String,
+    #[serde(default)]
+    pub path: Vec<serde_json::Value>,
+}
+
+/// 遇到 GraphQL 错误之后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorAction {
+    /// 等待之后原样重试
+    Retry,
+    /// 仓库被改名、删除、设为私有或者被 DMCA 下架，跳过整个仓库并记录原因。
+    SkipRepo,
+    /// 查询本身有问题，继续请求也没有意义。
+    Abort,
+}
+
+impl GraphqlError {
+    pub fn action(&self) -> ErrorAction {
+        match self.kind.as_deref() {
+            Some("RATE_LIMITED") => ErrorAction::Retry,
+            Some("NOT_FOUND") | Some("FORBIDDEN") => ErrorAction::SkipRepo,
+            _ => ErrorAction::Abort,
+        }
+    }
+}
+
+impl std::fmt::Display for GraphqlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] ", self.kind.as_deref().unwrap_or("UNKNOWN"))?;
+        if !self.path.is_empty() {
+            let path = self
+                .path
+                .iter()
+                .map(|p| p.as_str().map_or_else(|| p.to_string(), str::to_string))
+                .collect::<Vec<_>>()
+                .join("/");
+            write!(f, "{path}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl<Data> GraphqlResponse<Data> {
+    /// 所有错误中最严重的处理方式，没有错误时返回 None。
+    pub fn action(&self) -> Option<ErrorAction> {
+        self.errors.iter().map(GraphqlError::action).max()
+    }
+
+    /// 所有错误拼成一行，用于日志和元数据。
+    pub fn error_summary(&self) -> String {
+        self.errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// reqwest client 加上 token 池，每个请求单独带上 token。
+/// 多个 worker 共用同一个 client，请求统一经过 governor 节流。
+/// 同一台机器上的其他进程通过 `shared` 共享同一个 token 的额度。
+/// 请求次数、消耗和退避等待记在 `activity` 里，用于导出采集状态。
+pub struct GithubClient {
+    http: reqwest::blocking::Client,
+    pub tokens: TokenPool,
+    governor: Governor,
+    retry: RetryPolicy,
+    pub scheduler: ResetScheduler,
+    shared: SharedRateLimits,
+    pub activity: Activity,
+}
+
+impl GithubClient {
+    pub fn new(
+        http: reqwest::blocking::Client,
+        tokens: TokenPool,
+        governor: Governor,
+        retry: RetryPolicy,
+        shared: SharedRateLimits,
+    ) -> Self {
+        Self {
+            http,
+            tokens,
+            governor,
+            retry,
+            scheduler: ResetScheduler::new(),
+            shared,
+            activity: Activity::new(),
+        }
+    }
+
+    /// 用剩余额度最多的 token 发送请求，返回所用 token 的编号和请求本身的耗时（不含排队）。
+    /// 只有所有 token 都用完时才会等待到最早的重置时间，等待期间收到退出信号时返回 `Error::Interrupted`。
+    fn send<U: reqwest::IntoUrl>(
+        &self,
+        url: U,
+        body: &impl serde::Serialize,
+    ) -> Result<
+        (
+            usize,
+            Duration,
+            reqwest::Result<reqwest::blocking::Response>,
+        ),
+        Error,
+    > {
+        let (index, token) = loop {
+            // 其他 worker 可能刚把最后一个 token 标记为失效
+            if self.tokens.usable() == 0 {
+                return Err(Error::HttpStatus {
+                    status: reqwest::StatusCode::UNAUTHORIZED,
+                    body: "所有 token 都已失效".to_string(),
+                });
+            }
+
+            // 共享文件读写失败不影响采集，只是退回到各自计算额度。
+            let now = self.scheduler.server_now();
+            if let Err(e) = self.shared.sync(&self.tokens, now) {
+                warn!("共享的 rate limit 状态同步失败：{e}");
+            }
+
+            match self.tokens.acquire(now) {
+                Ok(picked) => break picked,
+                // 已经过了重置时间仍然没有额度，只能是预留额度把整个限额都占满了，等待没有意义。
+                Err(reset) if reset <= now => {
+                    return Err(Error::Config(format!(
+                        "所有 token 重置之后仍然没有额度可用，检查 reserve 配置：{:?}",
+                        self.tokens.reserve()
+                    )));
+                }
+                Err(reset) => {
+                    let wait = self.scheduler.until_reset(reset);
+                    log::info!(
+                        "所有 token 的额度都已用完（或只剩预留额度），等待 {}s 后重置。",
+                        wait.as_secs()
+                    );
+                    let _backoff = self.activity.backing_off("所有 token 的额度都已用完", wait);
+                    if !shutdown::sleep(wait) {
+                        return Err(Error::Interrupted);
+                    }
+                }
+            }
+        };
+
+        let (elapsed, response) = {
+            let _permit = self
+                .governor
+                .acquire(|| self.tokens.refill_per_sec(self.scheduler.server_now()));
+            let begin = std::time::Instant::now();
+            let response = self.http.post(url).bearer_auth(&token).json(body).send();
+            (begin.elapsed(), response)
+        };
+        self.activity.record_request(chrono::Utc::now());
+        let status = match &response {
+            Ok(r) => r.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::inc(Metric::Requests, &[("status", &status)], 1.0);
+
+        // 每个带额度信息的响应都更新一次对应 token 的 RateLimit
+        if let Ok(r) = &response {
+            self.scheduler.observe(r.headers());
+            if r.headers().contains_key("x-ratelimit-remaining") {
+                if let Ok(rate_limit) = RateLimit::try_from(r.headers()) {
+                    // 分数写法的预留额度要拿到 token 的限额才能检查
+                    self.tokens
+                        .reserve()
+                        .check(rate_limit.limit)
+                        .map_err(Error::Config)?;
+                    log::info!(
+                        "token #{index} limit: ({}/{}) remaining: {} 剩余重置时间： {} s",
+                        rate_limit.used,
+                        rate_limit.limit,
+                        rate_limit.remaining,
+                        self.scheduler.until_reset(rate_limit.reset).as_secs()
+                    );
+                    self.tokens.update(index, rate_limit);
+                    let token_label = index.to_string();
+                    let labels = [("token", token_label.as_str())];
+                    metrics::set(Metric::RateLimitLimit, &labels, rate_limit.limit as f64);
+                    metrics::set(
+                        Metric::RateLimitRemaining,
+                        &labels,
+                        rate_limit.remaining as f64,
+                    );
+                    metrics::set(Metric::RateLimitReset, &labels, rate_limit.reset as f64);
+                    if let Err(e) = self.shared.publish(&token, rate_limit) {
+                        warn!("共享的 rate limit 状态写入失败：{e}");
+                    }
+                }
+            }
+        }
+
+        Ok((index, elapsed, response))
+    }
+}
+
+/// 一次失败之后重试之前的等待
+enum Wait {
+    /// 退避等待，累计计入 `max_total_wait_secs`。
+    Backoff(u64),
+    /// 等到主要速率限制重置
+    Reset(u64),
+    /// 不用等待，比如换一个 token 直接重试。
+    None,
+}
+
+/// 一次成功的请求
+pub struct Posted<Data> {
+    pub response: GraphqlResponse<Data>,
+    /// 最终实际使用的窗口大小，502/504 重试时可能被缩小过。
+    pub window: i64,
+    /// 最后一次请求本身的耗时
+    pub elapsed: Duration,
+    /// 查询里 `rateLimit` 返回的消耗，查询没有带这个字段时为 None。
+    pub cost: Option<QueryCost>,
+}
+
 /// 重新定义 graphql_client::reqwest::post_graphql_blocking
-/// 主要增加了一个观察者闭包函数，观察内部的 header。
+/// 主要增加了 token 池的轮换和失败重试，返回值额外带上最终实际使用的窗口大小和耗时。
+///
+/// 各个响应码的处理方式、重试次数和等待时间由 `RetryPolicy` 决定。
+/// 响应里的 GraphQL 错误原样返回给调用方处理，只有 RATE_LIMITED 会在这里等待重试。
 pub fn post_graphql_blocking<Q: GraphQLQuery, U: reqwest::IntoUrl + Clone>(
-    client: &reqwest::blocking::Client,
+    client: &GithubClient,
     url: U,
     variables: Q::Variables,
-    // 目前只是一个粗略的实现，由于源库年久失修，这个
-    mut f: impl FnMut(&reqwest::header::HeaderMap) -> anyhow::Result<()>,
-) -> Result<graphql_client::Response<Q::ResponseData>, reqwest::Error>
+) -> Result<Posted<Q::ResponseData>, Error>
 where
     Q::Variables: Window,
+    Q::ResponseData: serde::de::DeserializeOwned,
+{
+    let mut window = variables.get_window();
+    post_graphql_with_window::<Q, U>(client, url, variables, &mut window)
+}
+
+/// 和 `post_graphql_blocking` 一样，另外把最后的窗口大小写回 `window`。
+///
+/// 重试全部失败时同样写回，调用方据此记住 502/504 之后缩小过的窗口，下次不用从超时的大小重新开始。
+pub fn post_graphql_with_window<Q: GraphQLQuery, U: reqwest::IntoUrl + Clone>(
+    client: &GithubClient,
+    url: U,
+    variables: Q::Variables,
+    window: &mut i64,
+) -> Result<Posted<Q::ResponseData>, Error>
+where
+    Q::Variables: Window,
+    Q::ResponseData: serde::de::DeserializeOwned,
 {
     let mut body = Q::build_query(variables);
+    let result = post_body::<Q, U>(client, url, &mut body);
+    *window = body.variables.get_window();
+    result
+}
 
-    let mut reqwest_response = client.post(url.clone()).json(&body).send();
+fn post_body<Q: GraphQLQuery, U: reqwest::IntoUrl + Clone>(
+    client: &GithubClient,
+    url: U,
+    body: &mut graphql_client::QueryBody<Q::Variables>,
+) -> Result<Posted<Q::ResponseData>, Error>
+where
+    Q::Variables: Window,
+    Q::ResponseData: serde::de::DeserializeOwned,
+{
+    let policy = &client.retry;
+
+    let mut waited_secs: u64 = 0;
+
+    for attempt in 1.. {
+        if shutdown::requested() {
+            return Err(Error::Interrupted);
+        }
+
+        let retry_step = attempt - 1;
+        let (token_index, elapsed, reqwest_response) = client.send(url.clone(), &*body)?;
 
-    for retry_step in 0..=6 {
         // https://docs.github.com/en/graphql/overview/rate-limits-and-node-limits-for-the-graphql-api#exceeding-the-rate-limit
         // 主要速率限制（Primary Rate Limit）：
         //
@@ -43,127 +328,266 @@ where
         // 待至少一分钟再进行重试。如果由于次要速率限制导致请求继续失败，等待重
         // 试的时间应按指数增加，最终在一定数量的重试后抛出错误。
         //
-        if let Ok(r) = &reqwest_response {
-            use reqwest::StatusCode as Code;
-            match r.status() {
-                Code::OK => {
-                    // 如果是 200，但是 x-ratelimit-remaining 为 0，那么就需要等待 x-ratelimit-reset 了。
-                    if r.headers()
-                        .get("x-ratelimit-remaining")
-                        .and_then(|v| v.to_str().ok())
-                        .and_then(|v| v.parse::<i32>().ok())
-                        .unwrap_or(0)
-                        > 0
-                    {
-                        break;
+        let (wait, error) = match reqwest_response {
+            // 如果是代理或者网络中断的情况，说实话我也没办法，只能等一等再试。
+            Err(e) if policy.retry_transport_errors => {
+                log::error!("reqwest_response is Err: {e:#?}");
+                (
+                    Wait::Backoff(policy.backoff_secs(retry_step)),
+                    Error::Network(e),
+                )
+            }
+            Err(e) => return Err(Error::Network(e)),
+            Ok(r) if r.status() == reqwest::StatusCode::OK => {
+                let headers = r.headers().clone();
+                // 读 body 时断开和连接失败一样处理
+                match r.text() {
+                    Err(e) if policy.retry_transport_errors => {
+                        log::error!("response body 读取失败: {e:#?}");
+                        (
+                            Wait::Backoff(policy.backoff_secs(retry_step)),
+                            Error::Network(e),
+                        )
+                    }
+                    Err(e) => return Err(Error::Network(e)),
+                    Ok(text) => {
+                        let response: GraphqlResponse<Q::ResponseData> =
+                            serde_json::from_str(&text)?;
+
+                        // 只有 RATE_LIMITED 才算被限流。remaining 为 0 的响应里的数据照样有效，
+                        // 额度用完的 token 由下一次 acquire 跳过。
+                        if response.action() != Some(ErrorAction::Retry) {
+                            let cost = QueryCost::from_response(&text);
+                            if let Some(cost) = &cost {
+                                client.governor.charge(cost.cost);
+                                client.activity.record_points(chrono::Utc::now(), cost.cost);
+                                log::info!(
+                                    "本次查询消耗 {} 分（{} 个节点），剩余 {}/{}，{} 重置",
+                                    cost.cost,
+                                    cost.node_count,
+                                    cost.remaining,
+                                    cost.limit,
+                                    cost.reset_at
+                                );
+                            }
+
+                            return Ok(Posted {
+                                response,
+                                window: body.variables.get_window(),
+                                elapsed,
+                                cost,
+                            });
+                        }
+
+                        if ratelimit_remaining(&headers) > 0 {
+                            // 还有额度却被限流，是次要速率限制。
+                            (
+                                Wait::Backoff(
+                                    policy.secondary_limit_secs(retry_step, retry_after(&headers)),
+                                ),
+                                Error::Graphql(response.error_summary()),
+                            )
+                        } else if client.tokens.has_available(client.scheduler.server_now()) {
+                            // 当前 token 的额度用完了，还有别的 token 可用时直接换一个重试。
+                            log::info!("token #{token_index} 的额度已用完，换用其他 token。");
+                            metrics::inc(Metric::Retries, &[("reason", "token_exhausted")], 1.0);
+                            (
+                                Wait::None,
+                                Error::RateLimited {
+                                    reset: ratelimit_reset(&headers),
+                                },
+                            )
+                        } else {
+                            // 所有 token 都用完了，等到重置时间。
+                            let reset = ratelimit_reset(&headers);
+                            (
+                                reset.map_or(
+                                    Wait::Backoff(policy.backoff_secs(retry_step)),
+                                    |reset| {
+                                        Wait::Reset(client.scheduler.until_reset(reset).as_secs())
+                                    },
+                                ),
+                                Error::RateLimited { reset },
+                            )
+                        }
                     }
                 }
-                Code::BAD_GATEWAY | Code::GATEWAY_TIMEOUT => {
-                    // https://github.com/orgs/community/discussions/24631#discussioncomment-3244785
-                    // 但在实际情况中，502 504 的情况一般是数据规模太大导致。
-                    // 如果是 502 504，那么就需要把会窗口大小改小。
-                    //  TODO 这里也意味着每一页的大小是不固定的。
-                    let size = body.variables.get_window();
-                    let new_size = (size * 2 / 3).max(1);
-                    // 此处每次缩小到原来的 2/3
-                    log::info!("收到 502 or 504 响应码，尝试缩小本次窗口大小到 {new_size}。");
-                    body.variables.set_window(new_size);
+            }
+            Ok(r) => {
+                let status = r.status();
+                let headers = r.headers().clone();
+                let text = r.text().unwrap_or_default();
+
+                if let Err(e) = dump_fail_response(status, &headers, &text) {
+                    warn!("失败响应保存失败：{e}");
                 }
-                code => {
-                    warn!("收到未处理过的意外响应码：{code}");
+
+                let error = Error::HttpStatus { status, body: text };
+
+                match policy.action(status) {
+                    // token 被撤销或者过期，还有其他 token 时不再用它，换一个重试。
+                    StatusAction::Abort
+                        if status == reqwest::StatusCode::UNAUTHORIZED
+                            && client.tokens.revoke(token_index) =>
+                    {
+                        log::warn!("token #{token_index} 已失效（{status}），之后不再使用。");
+                        metrics::inc(Metric::Retries, &[("reason", "token_revoked")], 1.0);
+                        (Wait::None, error)
+                    }
+                    StatusAction::Abort => return Err(error),
+                    StatusAction::Skip => {
+                        return Err(Error::RepoUnavailable(format!("响应码 {status}")))
+                    }
+                    StatusAction::SecondaryLimit => {
+                        // 403 也可能是主要速率限制，这时以重置时间为准。
+                        let wait = match ratelimit_reset(&headers) {
+                            Some(reset) if ratelimit_remaining(&headers) == 0 => {
+                                Wait::Reset(client.scheduler.until_reset(reset).as_secs())
+                            }
+                            _ => Wait::Backoff(
+                                policy.secondary_limit_secs(retry_step, retry_after(&headers)),
+                            ),
+                        };
+                        (wait, error)
+                    }
+                    StatusAction::Backoff => {
+                        (Wait::Backoff(policy.backoff_secs(retry_step)), error)
+                    }
+                    StatusAction::ShrinkWindow => {
+                        // 如果是 502 504，那么就需要把会窗口大小改小。
+                        //  TODO 这里也意味着每一页的大小是不固定的。
+                        let new_size = policy.shrink(body.variables.get_window());
+                        log::info!("收到 {status} 响应码，尝试缩小本次窗口大小到 {new_size}。");
+                        metrics::inc(Metric::WindowShrinks, &[("cause", "gateway")], 1.0);
+                        body.variables.set_window(new_size);
+                        (Wait::Backoff(policy.backoff_secs(retry_step)), error)
+                    }
                 }
             }
+        };
+
+        // 重试次数或者累计退避超过上限，交给调用方记录下来，继续下一个仓库。
+        // 换 token 同样算一次尝试；等待主要速率限制重置是正常的节奏，不计入累计退避。
+        let (retry_secs, backoff_secs) = match wait {
+            Wait::Backoff(secs) => (secs, secs),
+            Wait::Reset(secs) => (secs, 0),
+            Wait::None => (0, 0),
+        };
+        if attempt >= policy.max_attempts
+            || waited_secs.saturating_add(backoff_secs) > policy.max_total_wait_secs
+        {
+            log::error!("第 {attempt} 次请求仍然失败，不再重试：{error}");
+            return Err(error);
+        }
+        if let Wait::None = wait {
+            continue;
         }
 
-        // github 给的时间戳是加州西 7 区的时间，所以需要转换一下。
-        let time_zone = chrono::FixedOffset::west_opt(7 * 3600).unwrap();
-
-        // 第一重限制可能提供的时间根据实际情况，第一层的 5000 次限制每个小时都
-        // 是跑不满的，因为每个请求拉满 100 的 nodes 实际给 github 的计算时间还
-        // 是偏多了一点。所以门槛都在计算复杂度上。
-        let x_ratelimit_reset: u64 = reqwest_response
-            .as_ref()
-            .ok()
-            .and_then(|r| r.headers().get("x-ratelimit-reset"))
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(chrono::Utc::now().with_timezone(&time_zone).timestamp() as u64);
-
-        // 第二重限制可能提供的时间
-        let retry_after: u64 = reqwest_response
-            .as_ref()
-            .ok()
-            .and_then(|r| r.headers().get("retry-after"))
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0);
-
-        let retry_secs = retry_after
-            .max(x_ratelimit_reset - chrono::Utc::now().timestamp() as u64)
-            // 假设基础的重试时间是 30 秒
-            // 累计前面的 30 + 60 + 120 + 240 + 480 + 960 + 1920 = 3810 秒约等于等待一小时。
-            // 简单算就是 3840（30 << 7）秒 - 30 秒
-            // take max time on retry.
-            .max(crate::BASE_RETRY_SECS << retry_step);
-
-        log::info!("服务器请求被阻止，尝试 {retry_secs}s 后重试任务。");
-
-        // dump the response body before retries to  logs/<datetime>_fail.json
-        // 此处会移动消耗掉 reqwest_response
-        dump_fail_request(reqwest_response);
-
-        thread::sleep(Duration::from_secs(retry_secs));
-
-        reqwest_response = client.post(url.clone()).json(&body).send();
+        let delay = policy.with_jitter(retry_secs);
+        log::info!(
+            "服务器请求被阻止（{error}），尝试 {}s 后重试任务。",
+            delay.as_secs()
+        );
+        // 失败响应的 body 可能很长，退避原因里只写响应码。
+        let reason = match &error {
+            Error::HttpStatus { status, .. } => format!("响应码 {status}"),
+            e => e.to_string(),
+        };
+        let _backoff = client.activity.backing_off(reason, delay);
+        metrics::inc(Metric::Retries, &[("reason", error.kind())], 1.0);
+        if !shutdown::sleep(delay) {
+            return Err(Error::Interrupted);
+        }
+        waited_secs = waited_secs.saturating_add(backoff_secs);
     }
 
-    // 如果是代理或者网络中断的情况，说实话我也没办法。
-    let response = reqwest_response.expect("重试间隔 1h 之后还是失败，需要进一步寻找原因。");
+    unreachable!("重试循环只会从内部返回")
+}
+
+fn ratelimit_remaining(headers: &reqwest::header::HeaderMap) -> i32 {
+    headers
+        .get("x-ratelimit-remaining")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(0)
+}
 
-    // take response headers out
-    let _ = f(response.headers());
+fn ratelimit_reset(headers: &reqwest::header::HeaderMap) -> Option<i64> {
+    headers
+        .get("x-ratelimit-reset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
 
-    response.json()
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers
+        .get("retry-after")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
 }
 
-fn dump_fail_request(reqwest_response: Result<reqwest::blocking::Response, reqwest::Error>) {
-    match reqwest_response {
-        Ok(r) => {
-            log::error!(
-                "本次失败响应状态码：{code:?}，响应头：{head:#?}",
-                code = r.status(),
-                head = r.headers()
-            );
+/// 把失败的响应记到日志里，github 返回的 html 错误页单独存到 `log/<datetime>_fail.html`。
+fn dump_fail_response(
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+    body: &str,
+) -> std::io::Result<()> {
+    log::error!("本次失败响应状态码：{status:?}，响应头：{headers:#?}");
+
+    if body.starts_with("<!DOCTYPE html>") {
+        let log_dir = std::path::Path::new("log");
+        if !log_dir.exists() {
+            std::fs::create_dir(log_dir)?;
+        }
 
-            let body = r.text().unwrap_or("respnse.text() failed".to_owned());
+        let filename = log_dir.join(format!(
+            "{}_fail.html",
+            chrono::Utc::now().format("%Y-%m-%d_%H_%M_%S")
+        ));
 
-            if body.starts_with("<!DOCTYPE html>") {
-                let log_dir = std::path::Path::new("log");
-                if !log_dir.exists() {
-                    std::fs::create_dir(log_dir).unwrap();
-                }
+        let mut file = std::fs::File::create(&filename)?;
 
-                let filename = log_dir.join(format!(
-                    "{}_fail.html",
-                    chrono::Utc::now().format("%Y-%m-%d_%H_%M_%S")
-                ));
+        file.write_all(body.as_bytes())?;
 
-                let mut file = std::fs::File::create(&filename).unwrap();
+        log::error!(
+            "本次失败响应体的内容为： {p}",
+            p = filename.as_path().to_string_lossy()
+        );
+    } else {
+        log::error!("本次失败响应体的内容为： {body}");
+    }
 
-                file.write_all(body.as_bytes()).unwrap();
+    Ok(())
+}
 
-                log::error!(
-                    "本次失败响应体的内容为： {p}",
-                    p = filename.as_path().to_string_lossy()
-                );
-            } else {
-                log::error!("本次失败响应体的内容为： {body}");
-            }
-        }
-        Err(e) => {
-            log::error!("reqwest_response is Err: {e:#?}");
-        }
-    };
+#[test]
+fn test_classify_graphql_errors() -> serde_json::Result<()> {
+    let not_found: GraphqlResponse<serde_json::Value> = serde_json::from_str(
+        r#"{"data":{"repository":null},"errors":[{"type":"NOT_FOUND","path":["repository"],
+            "locations":[{"line":7,"column":3}],
+            "message":"Could not resolve to a Repository with the name 'o/r'."}]}"#,
+    )?;
+    assert_eq!(not_found.action(), Some(ErrorAction::SkipRepo));
+    assert_eq!(
+        not_found.error_summary(),
+        "[NOT_FOUND] repository: Could not resolve to a Repository with the name 'o/r'."
+    );
+
+    let rate_limited: GraphqlResponse<serde_json::Value> = serde_json::from_str(
+        r#"{"errors":[{"type":"RATE_LIMITED","message":"API rate limit exceeded"}]}"#,
+    )?;
+    assert_eq!(rate_limited.action(), Some(ErrorAction::Retry));
+
+    // 没有 type 的错误（比如查询语法错误）直接中止，和其他错误同时出现时取最严重的
+    let mixed: GraphqlResponse<serde_json::Value> = serde_json::from_str(
+        r#"{"errors":[{"type":"FORBIDDEN","message":"a"},{"message":"Field 'x' doesn't exist"}]}"#,
+    )?;
+    assert_eq!(mixed.action(), Some(ErrorAction::Abort));
+
+    let ok: GraphqlResponse<serde_json::Value> = serde_json::from_str(r#"{"data":{}}"#)?;
+    assert_eq!(ok.action(), None);
+
+    Ok(())
 }
 
 #[test]
@@ -205,8 +629,271 @@ fn test_time_stamp() {
     println!("UTC: {}", nowtime.to_rfc3339());
 }
 
+/// 按顺序对每个连接回一个响应的本地服务器
+#[cfg(test)]
+fn serve(responses: Vec<String>) -> (String, std::thread::JoinHandle<()>) {
+    use std::io::{BufRead, BufReader};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, server)
+}
+
+/// 带额度标头的响应
+#[cfg(test)]
+fn response(status: &str, remaining: i64, reset: i64, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nx-ratelimit-limit: 5000\r\nx-ratelimit-remaining: {remaining}\r\n\
+         x-ratelimit-used: {}\r\nx-ratelimit-reset: {reset}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        5000 - remaining,
+        body.len()
+    )
+}
+
+#[cfg(test)]
+struct Probe;
+
+#[cfg(test)]
+#[derive(serde::Serialize)]
+struct ProbeVariables(i64);
+
+#[cfg(test)]
+impl Window for ProbeVariables {
+    fn get_window(&self) -> i64 {
+        self.0
+    }
+
+    fn set_window(&mut self, window: i64) {
+        self.0 = window;
+    }
+}
+
+#[cfg(test)]
+impl GraphQLQuery for Probe {
+    type Variables = ProbeVariables;
+    type ResponseData = serde_json::Value;
+
+    fn build_query(variables: Self::Variables) -> graphql_client::QueryBody<Self::Variables> {
+        graphql_client::QueryBody {
+            variables,
+            query: "{ viewer { login } }",
+            operation_name: "Probe",
+        }
+    }
+}
+
+/// 每个测试单独的共享额度文件，避免互相影响。
+#[cfg(test)]
+fn test_client(name: &str, tokens: &[&str], retry: RetryPolicy) -> GithubClient {
+    use crate::token_pool::Reserve;
+
+    let path = std::env::temp_dir().join(format!(
+        "graphql_github_{name}_test_{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    GithubClient::new(
+        reqwest::blocking::Client::new(),
+        TokenPool::new(
+            tokens.iter().map(|t| t.to_string()).collect(),
+            Reserve::None,
+        ),
+        Governor::new(Default::default()),
+        retry,
+        SharedRateLimits::new(path),
+    )
+}
+
+#[test]
+fn test_send_publishes_usage_under_real_token() {
+    use crate::token_pool::Reserve;
+
+    let path = std::env::temp_dir().join(format!(
+        "graphql_github_send_shared_test_{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let reset = chrono::Utc::now().timestamp() + 600;
+    let (url, server) = serve(vec![response("200 OK", 0, reset, "{}")]);
+
+    let client = GithubClient::new(
+        reqwest::blocking::Client::new(),
+        TokenPool::new(vec!["github_pat_real".into()], Reserve::None),
+        Governor::new(Default::default()),
+        RetryPolicy::default(),
+        SharedRateLimits::new(path.clone()),
+    );
+    let (_, _, response) = client.send(url, &serde_json::json!({})).unwrap();
+    assert!(response.unwrap().status().is_success());
+    server.join().unwrap();
+
+    // 另一个用同一个 token 的进程同步之后，知道这个 token 已经用完了。
+    let other_pool = TokenPool::new(vec!["github_pat_real".into()], Reserve::None);
+    let now = chrono::Utc::now().timestamp();
+    SharedRateLimits::new(path.clone())
+        .sync(&other_pool, now)
+        .unwrap();
+    assert_eq!(other_pool.acquire(now), Err(reset));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
 #[test]
-fn text_time_zone() {
-    let fo = chrono::FixedOffset::west_opt(7 * 3600).unwrap();
-    dbg!(chrono::Utc::now().with_timezone(&fo).to_rfc3339());
+fn test_switching_token_counts_as_attempt() {
+    let reset = chrono::Utc::now().timestamp() + 600;
+    let (url, server) = serve(vec![response(
+        "200 OK",
+        0,
+        reset,
+        r#"{"errors":[{"type":"RATE_LIMITED","message":"API rate limit exceeded"}]}"#,
+    )]);
+
+    // 第二个 token 还没用过，看起来一直可用；换 token 也要受 max_attempts 限制。
+    let retry = RetryPolicy {
+        max_attempts: 1,
+        ..Default::default()
+    };
+    let client = test_client("switch_token", &["a", "b"], retry);
+    let result = post_graphql_blocking::<Probe, _>(&client, url, ProbeVariables(100));
+    server.join().unwrap();
+
+    assert!(matches!(
+        result,
+        Err(Error::RateLimited { reset: Some(r) }) if r == reset
+    ));
+}
+
+#[test]
+fn test_page_using_last_point_is_kept() {
+    let reset = chrono::Utc::now().timestamp() + 600;
+    let (url, server) = serve(vec![response(
+        "200 OK",
+        0,
+        reset,
+        r#"{"data":{"viewer":{"login":"octocat"}}}"#,
+    )]);
+
+    let client = test_client("last_point", &["a"], RetryPolicy::default());
+    let posted = post_graphql_blocking::<Probe, _>(&client, url, ProbeVariables(100)).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(posted.response.data.unwrap()["viewer"]["login"], "octocat");
+    // 额度用完的 token 留给下一次 acquire 处理
+    assert_eq!(
+        client.tokens.acquire(client.scheduler.server_now()),
+        Err(reset)
+    );
+}
+
+#[test]
+fn test_revoked_token_is_dropped_from_pool() {
+    let reset = chrono::Utc::now().timestamp() + 600;
+    let unauthorized =
+        "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    // 还有其他 token 时换一个继续
+    let (url, server) = serve(vec![
+        unauthorized.to_string(),
+        response("200 OK", 4999, reset, r#"{"data":{}}"#),
+    ]);
+    let client = test_client("revoked", &["a", "b"], RetryPolicy::default());
+    let result = post_graphql_blocking::<Probe, _>(&client, url, ProbeVariables(100));
+    server.join().unwrap();
+    assert!(result.is_ok());
+    assert_eq!(client.tokens.usable(), 1);
+
+    // 最后一个 token 也失效时中止
+    let (url, server) = serve(vec![unauthorized.to_string()]);
+    let client = test_client("revoked_last", &["a"], RetryPolicy::default());
+    let result = post_graphql_blocking::<Probe, _>(&client, url, ProbeVariables(100));
+    server.join().unwrap();
+    assert!(matches!(result, Err(ref e) if e.is_fatal()));
+}
+
+#[test]
+fn test_reserve_covering_whole_limit_is_config_error() {
+    use crate::token_pool::Reserve;
+
+    let path = std::env::temp_dir().join(format!(
+        "graphql_github_reserve_test_{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let new_client = || {
+        GithubClient::new(
+            reqwest::blocking::Client::new(),
+            TokenPool::new(vec!["a".into()], Reserve::Points(5000)),
+            Governor::new(Default::default()),
+            RetryPolicy::default(),
+            SharedRateLimits::new(path.clone()),
+        )
+    };
+
+    // 第一次拿到限额时检查出来
+    let (url, server) = serve(vec![response(
+        "200 OK",
+        0,
+        chrono::Utc::now().timestamp() + 600,
+        "{}",
+    )]);
+    let result = new_client().send(url, &serde_json::json!({}));
+    server.join().unwrap();
+    assert!(matches!(result, Err(Error::Config(_))));
+
+    // 已经过了重置时间仍然没有额度时不再等待
+    let client = new_client();
+    let now = client.scheduler.server_now();
+    client
+        .tokens
+        .update(0, RateLimit::new(5000, 0, 5000, now - 1));
+    assert!(matches!(
+        client.send("http://127.0.0.1:9/graphql", &serde_json::json!({})),
+        Err(Error::Config(_))
+    ));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_shrunk_window_is_returned_when_retries_fail() {
+    let bad_gateway = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (url, server) = serve(vec![bad_gateway.to_string(), bad_gateway.to_string()]);
+
+    let retry = RetryPolicy {
+        max_attempts: 2,
+        base_delay_secs: 0,
+        ..Default::default()
+    };
+    let client = test_client("shrink_give_up", &["a"], retry);
+    let mut window = 100;
+    let result =
+        post_graphql_with_window::<Probe, _>(&client, url, ProbeVariables(100), &mut window);
+    server.join().unwrap();
+
+    assert!(matches!(result, Err(Error::HttpStatus { .. })));
+    // 100 -> 66 -> 44，下次从 44 开始
+    assert_eq!(window, 44);
 }
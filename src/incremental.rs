@@ -0,0 +1,300 @@
+// 增量同步：按 updatedAt 倒序拉取高水位之后更新过的节点，按 url 合并进已有的分页。
+// 全量采集按 CREATED_AT ASC 续爬，已经采过的节点之后被编辑、新增评论或者被关闭都不会再采到。
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::cost::CostTotals;
+use crate::graphql_client_ext::GithubClient;
+use crate::layout;
+use crate::ledger::{DeltaRecord, RepoLedger, TaskLedger};
+use crate::metrics;
+use crate::progress::Progress;
+use crate::query::{self, PaginatedTask, QueryOrder};
+use crate::shutdown;
+use crate::util::{self, TaskType};
+
+fn node_url(node: &Value) -> Option<&str> {
+    node.get("url")?.as_str()
+}
+
+fn node_updated_at(node: &Value) -> Option<DateTime<Utc>> {
+    let updated_at = node.get("updatedAt")?.as_str()?;
+    DateTime::parse_from_rfc3339(updated_at)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn nodes_pointer(connection: &str) -> String {
+    format!("/repository/{connection}/nodes")
+}
+
+/// 同步一个仓库的一类任务，最多请求 `step_limit` 页。
+///
+/// 只有一直翻到高水位（或者没有下一页）时才会推进高水位，
+/// 否则下一次会从同一个高水位重新同步，合并是幂等的。
+#[allow(clippy::too_many_arguments)]
+pub fn sync<T: PaginatedTask>(
+    root: &Path,
+    repo_owner: &str,
+    repo_name: &str,
+    client: &GithubClient,
+    ledger: &mut RepoLedger,
+    since: DateTime<Utc>,
+    step_limit: i32,
+    progress: &Progress,
+) -> Result<()> {
+    let task_type = T::TASK_TYPE;
+    let connection = T::CONNECTION;
+
+    log::info!("[{task_type}] [{repo_owner}] [{repo_name}] 增量同步 {since} 之后更新过的节点");
+
+    let mut cursor: Option<String> = None;
+    let mut changed: Vec<Value> = Vec::new();
+    let mut new_mark = since;
+    let mut reached_mark = false;
+    let mut cost = CostTotals::default();
+    let mut window = ledger
+        .task(task_type)
+        .map(|task| task.window)
+        .unwrap_or_default();
+
+    for i in 0..step_limit {
+        // 停下时已经拿到的变更照常合并，高水位不推进，下次从同一个高水位重新同步。
+        if i > 0 && shutdown::requested() {
+            break;
+        }
+
+        let result = query::single_query::<T>(
+            repo_owner,
+            repo_name,
+            &cursor,
+            QueryOrder::UpdatedSince(since),
+            &mut window,
+            client,
+        );
+        // 失败时同样记下窗口，crawl_repo 记录失败时会一起保存。
+        ledger.task_mut(task_type).window = window;
+        let result = result?;
+
+        cost += result.cost;
+
+        let page = serde_json::to_value(&result.response_data)?;
+        let nodes = page
+            .pointer(&nodes_pointer(connection))
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+
+        for node in nodes {
+            match node_updated_at(&node) {
+                Some(updated_at) if updated_at > since => {
+                    new_mark = new_mark.max(updated_at);
+                    changed.push(node);
+                }
+                // 按更新时间倒序排列，后面的只会更旧。
+                _ => reached_mark = true,
+            }
+        }
+
+        log::info!(
+            "[{task_type}] [{repo_owner}] [{repo_name}] 增量 step {i:03} 累计变更节点 {}",
+            changed.len()
+        );
+        progress.at(client, repo_owner, repo_name, task_type, Some(i));
+
+        if !result.has_next_page {
+            reached_mark = true;
+        }
+        if reached_mark {
+            break;
+        }
+
+        cursor = result.query_cursor;
+    }
+
+    let task_path = util::task_dir(root, repo_owner, repo_name, task_type);
+    let task = ledger.task_mut(task_type);
+    task.window = window;
+    task.cost += cost;
+
+    let (replaced, appended) = merge_changes(&task_path, task, task_type, changed, since)?;
+
+    if reached_mark {
+        task.high_water_mark = Some(new_mark);
+    } else {
+        log::warn!(
+            "[{task_type}] [{repo_owner}] [{repo_name}] 达到 step_limit 仍未翻到高水位，下次从 {since} 重新同步"
+        );
+    }
+
+    metrics::observe_task(repo_owner, repo_name, task_type, task);
+    ledger.save(root, repo_owner, repo_name)?;
+
+    log::info!(
+        "[{task_type}] [{repo_owner}] [{repo_name}] 增量同步完成，替换 {replaced} 个，新增 {appended} 个，高水位 {:?}",
+        ledger.task(task_type).and_then(|t| t.high_water_mark)
+    );
+
+    Ok(())
+}
+
+/// 把变更节点按 url 替换进已有的分页和变更页，找不到的写成一页新的变更页。
+///
+/// 返回替换和新增的节点数。
+fn merge_changes(
+    task_path: &Path,
+    task: &mut TaskLedger,
+    task_type: TaskType,
+    changed: Vec<Value>,
+    since: DateTime<Utc>,
+) -> Result<(usize, usize)> {
+    let connection = task_type.connection_name();
+    let pointer = nodes_pointer(connection);
+
+    // 同一个节点出现多次时保留最先出现的，也就是最新的那个。
+    let mut order: Vec<String> = Vec::new();
+    let mut pending: HashMap<String, Value> = HashMap::new();
+    for node in changed {
+        let Some(url) = node_url(&node).map(str::to_string) else {
+            continue;
+        };
+        if let std::collections::hash_map::Entry::Vacant(entry) = pending.entry(url.clone()) {
+            order.push(url);
+            entry.insert(node);
+        }
+    }
+
+    let mut replaced = 0;
+
+    let files = task.data_files().map(str::to_string).collect::<Vec<_>>();
+    for file in files {
+        if pending.is_empty() {
+            break;
+        }
+
+        let path = task_path.join(&file);
+        let mut page: Value = serde_json::from_reader(
+            fs::File::open(&path).context(format!("{} 打开失败", path.display()))?,
+        )
+        .context(format!("{} 解析失败", path.display()))?;
+
+        let Some(nodes) = page.pointer_mut(&pointer).and_then(Value::as_array_mut) else {
+            continue;
+        };
+
+        let mut replaced_here = 0;
+        for node in nodes.iter_mut() {
+            let Some(url) = node_url(node).map(str::to_string) else {
+                continue;
+            };
+            if let Some(new_node) = pending.remove(&url) {
+                *node = new_node;
+                replaced_here += 1;
+            }
+        }
+
+        if replaced_here > 0 {
+            let bytes = serde_json::to_string(&page)?;
+            util::write_atomic(&path, bytes.as_bytes())
+                .context(format!("{} 写入失败", path.display()))?;
+            metrics::page_written(task_type, replaced_here, bytes.len());
+            replaced += replaced_here;
+        }
+    }
+
+    let unmatched = order
+        .into_iter()
+        .filter_map(|url| pending.remove(&url))
+        .collect::<Vec<_>>();
+    let appended = unmatched.len();
+
+    if appended > 0 {
+        let fetched_at = Utc::now();
+        let file = layout::delta_file_name(fetched_at);
+        let page = serde_json::json!({ "repository": { connection: { "nodes": unmatched } } });
+
+        fs::create_dir_all(task_path).context(format!("{task_path:?} 路径创建出现问题"))?;
+        let bytes = serde_json::to_string(&page)?;
+        util::write_atomic(&task_path.join(&file), bytes.as_bytes())
+            .context(format!("{file} 写入失败"))?;
+        metrics::page_written(task_type, appended, bytes.len());
+
+        task.deltas.push(DeltaRecord {
+            file,
+            item_count: appended,
+            since,
+            fetched_at,
+        });
+    }
+
+    Ok((replaced, appended))
+}
+
+#[test]
+fn test_merge_changes() -> Result<()> {
+    use crate::ledger::StepRecord;
+
+    let task_path = std::env::temp_dir().join(format!("incremental_test_{}", std::process::id()));
+    fs::create_dir_all(&task_path)?;
+
+    let node = |n: i32, title: &str| {
+        serde_json::json!({
+            "url": format!("https://github.com/o/r/issues/{n}"),
+            "title": title,
+            "updatedAt": "2024-01-01T00:00:00Z",
+        })
+    };
+    let page = serde_json::json!({ "repository": { "issues": {
+        "pageInfo": {}, "nodes": [node(1, "old"), node(2, "old")]
+    } } });
+    fs::write(task_path.join("000000_first_cursor.json"), page.to_string())?;
+
+    let mut task = TaskLedger::default();
+    task.record_step(StepRecord {
+        step: 0,
+        cursor: None,
+        end_cursor: None,
+        file: "000000_first_cursor.json".to_string(),
+        item_count: 2,
+        window: Some(100),
+        fetched_at: Utc::now(),
+        cost: None,
+    });
+
+    let since = Utc::now();
+    let changed = vec![node(2, "newest"), node(3, "new"), node(2, "older edit")];
+    let (replaced, appended) = merge_changes(
+        &task_path,
+        &mut task,
+        TaskType::ClosedIssues,
+        changed,
+        since,
+    )?;
+
+    assert_eq!((replaced, appended), (1, 1));
+
+    let page: Value = serde_json::from_str(&fs::read_to_string(
+        task_path.join("000000_first_cursor.json"),
+    )?)?;
+    assert_eq!(
+        page.pointer("/repository/issues/nodes/1/title"),
+        Some(&"newest".into())
+    );
+
+    assert_eq!(task.deltas.len(), 1);
+    let delta: Value =
+        serde_json::from_str(&fs::read_to_string(task_path.join(&task.deltas[0].file))?)?;
+    assert_eq!(
+        delta.pointer("/repository/issues/nodes/0/title"),
+        Some(&"new".into())
+    );
+
+    fs::remove_dir_all(&task_path)?;
+
+    Ok(())
+}
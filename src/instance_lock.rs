@@ -0,0 +1,154 @@
+// 同一个输出目录只允许一个会写入的实例。
+//
+// 守护脚本会循环重启采集程序，一不小心就会有两份同时写同一个 `output/`，
+// 分页文件和元数据会互相覆盖。写入之前先在 `<output>/crawler.lock` 上加排他锁，
+// 文件里记录持有者的 PID、主机名和启动时间，方便排查。
+//
+// 锁是操作系统的文件锁，进程退出（包括崩溃）时自动释放，所以文件里留有内容但没有被锁住，
+// 就说明上一个实例没有正常退出，这种过期的锁直接接管。
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+const LOCK_FILE: &str = "crawler.lock";
+
+/// 锁文件里记录的持有者
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: hostname(),
+            started_at: chrono::Utc::now(),
+        }
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "pid {} on {}, started at {}",
+            self.pid, self.host, self.started_at
+        )
+    }
+}
+
+/// 持有期间其他实例无法写入同一个输出目录，drop 时释放。
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    /// 拿不到锁时报错，并说明是谁在使用这个目录。
+    pub fn acquire(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root).context(format!("{} 路径创建出现问题", root.display()))?;
+
+        let path = root.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("{} 打开失败", path.display()))?;
+
+        if let Err(e) = file.try_lock() {
+            let holder = read_owner(&mut file).map_or("unknown".to_string(), |o| o.to_string());
+            bail!(
+                "{} 正在被另一个实例使用（{holder}）：{e}。可以用 `status` 只读查看进度",
+                root.display()
+            );
+        }
+
+        if let Some(stale) = read_owner(&mut file) {
+            log::warn!("上一个实例没有正常退出，接管过期的锁（{stale}）");
+        }
+
+        let owner = LockOwner::current();
+        file.set_len(0)?;
+        file.rewind()?;
+        serde_json::to_writer(&mut file, &owner)?;
+        file.flush()?;
+
+        log::info!("已锁定 {}（{owner}）", root.display());
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // 不删除锁文件，否则另一个实例可能锁住已经被删掉的文件。清空内容表示正常退出。
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// 当前持有锁的实例，没有实例在写入时返回 None，供只读命令提示。
+pub fn holder(root: &Path) -> Option<LockOwner> {
+    let mut file = File::open(root.join(LOCK_FILE)).ok()?;
+    match file.try_lock_shared() {
+        // 能加上共享锁说明没有实例持有排他锁
+        Ok(()) => {
+            let _ = file.unlock();
+            None
+        }
+        Err(_) => read_owner(&mut file),
+    }
+}
+
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[test]
+fn test_second_instance_is_refused() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("graphql_github_lock_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let lock = InstanceLock::acquire(&root)?;
+    let holder_now = holder(&root).expect("应该能看到持有者");
+    assert_eq!(holder_now.pid, std::process::id());
+
+    // 同一个进程里再打开一次也是独立的文件锁
+    let error = InstanceLock::acquire(&root)
+        .err()
+        .expect("第二个实例应该拿不到锁");
+    assert!(error.to_string().contains("正在被另一个实例使用"));
+
+    drop(lock);
+    assert!(holder(&root).is_none());
+
+    // 过期的锁：文件里有内容但没有被锁住，可以直接接管。
+    fs::write(
+        root.join(LOCK_FILE),
+        serde_json::to_string(&LockOwner::current())?,
+    )?;
+    drop(InstanceLock::acquire(&root)?);
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
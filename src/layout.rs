@@ -0,0 +1,248 @@
+// 输出目录的布局版本，记录在 `<root>/layout.json`。
+//
+// - v1：`NNN_<cursor>.json`，cursor 原样写进文件名（`/` 会变成子目录），step 只有三位，超过 999 之后字典序就乱了。
+// - v2：`NNNNNN_<base64url(cursor)>.json`，文件名只包含 `[0-9A-Za-z_-]`，字典序就是 step 的顺序。
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::fs;
+use std::path::Path;
+
+use crate::ledger::RepoLedger;
+use crate::util::{self, TaskType};
+
+pub const CURRENT_VERSION: u32 = 2;
+
+const LAYOUT_FILE_NAME: &str = "layout.json";
+
+// 第一页没有 cursor，文件名里用这个占位。
+const FIRST_CURSOR: &str = "first_cursor";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct LayoutInfo {
+    version: u32,
+}
+
+pub fn encode_cursor(cursor: &Option<String>) -> String {
+    match cursor {
+        Some(cursor) => URL_SAFE_NO_PAD.encode(cursor),
+        None => FIRST_CURSOR.to_string(),
+    }
+}
+
+pub fn decode_cursor(encoded: &str) -> Result<Option<String>> {
+    if encoded == FIRST_CURSOR {
+        return Ok(None);
+    }
+
+    let bytes = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .context(format!("{encoded} 不是合法的 cursor 编码"))?;
+
+    Ok(Some(String::from_utf8(bytes)?))
+}
+
+/// 分页文件名：`<step:06>_<encoded cursor>.json`
+pub fn page_file_name(step: i32, cursor: &Option<String>) -> String {
+    format!("{step:06}_{}.json", encode_cursor(cursor))
+}
+
+/// 增量同步的变更页文件名：`delta_<UTC 时间>.json`，不会和分页文件名冲突。
+pub fn delta_file_name(fetched_at: chrono::DateTime<chrono::Utc>) -> String {
+    format!("delta_{}.json", fetched_at.format("%Y%m%dT%H%M%S%.3fZ"))
+}
+
+/// 读取输出目录的布局版本；没有版本文件但已经有数据的目录视为 v1。
+pub fn read_version(root: &Path) -> Result<u32> {
+    let path = root.join(LAYOUT_FILE_NAME);
+
+    if path.exists() {
+        let file = fs::File::open(&path).context(format!("{path:?} 打开失败"))?;
+        let info: LayoutInfo =
+            serde_json::from_reader(file).context(format!("{path:?} 解析失败"))?;
+        return Ok(info.version);
+    }
+
+    let has_data = fs::read_dir(root)
+        .map(|mut entries| entries.any(|e| e.is_ok_and(|e| e.path().is_dir())))
+        .unwrap_or(false);
+
+    Ok(if has_data { 1 } else { CURRENT_VERSION })
+}
+
+fn write_version(root: &Path, version: u32) -> Result<()> {
+    fs::create_dir_all(root).context(format!("{root:?} 路径创建出现问题"))?;
+
+    let path = root.join(LAYOUT_FILE_NAME);
+    util::write_atomic(
+        &path,
+        serde_json::to_string(&LayoutInfo { version })?.as_bytes(),
+    )
+    .context(format!("{path:?} 写入失败"))
+}
+
+/// 采集前确认输出目录是当前版本的布局，新目录直接写入版本号。
+pub fn ensure_current(root: &Path) -> Result<()> {
+    match read_version(root)? {
+        CURRENT_VERSION => write_version(root, CURRENT_VERSION),
+        version if version < CURRENT_VERSION => bail!(
+            "{} 的布局版本为 v{version}，请先运行 `migrate` 升级到 v{CURRENT_VERSION}",
+            root.display()
+        ),
+        version => bail!(
+            "{} 的布局版本 v{version} 比程序支持的 v{CURRENT_VERSION} 更新",
+            root.display()
+        ),
+    }
+}
+
+/// 把旧布局的输出目录整体改写为当前布局。
+///
+/// 续爬状态都在元数据里，迁移只是按元数据逐页改名并回写文件名。
+/// 中途中断的话再跑一次即可，已经改过名的页会被识别出来。
+pub fn migrate(root: &Path) -> Result<()> {
+    let version = read_version(root)?;
+    if version > CURRENT_VERSION {
+        bail!(
+            "{} 的布局版本 v{version} 比程序支持的 v{CURRENT_VERSION} 更新",
+            root.display()
+        );
+    }
+
+    let mut renamed = 0;
+
+    for entry in fs::read_dir(root).context(format!("{root:?} 文件夹不存在"))? {
+        let repo_path = entry?.path();
+        if !repo_path.is_dir() {
+            continue;
+        }
+
+        // 目录名为 `<owner>_<repo>`，github 的 owner 不允许出现下划线。
+        let Some((owner, repo)) = repo_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('_'))
+        else {
+            log::warn!("跳过无法识别的目录 {}", repo_path.display());
+            continue;
+        };
+
+        let mut ledger = RepoLedger::load(root, owner, repo)?;
+
+        for task_type in TaskType::ALL {
+            let Some(task) = ledger.tasks.get_mut(&task_type.to_string()) else {
+                continue;
+            };
+            let task_path = util::task_dir(root, owner, repo, task_type);
+
+            for record in &mut task.steps {
+                let new_file = page_file_name(record.step, &record.cursor);
+                if record.file == new_file {
+                    continue;
+                }
+
+                let old_path = task_path.join(&record.file);
+                let new_path = task_path.join(&new_file);
+
+                if old_path.exists() {
+                    fs::rename(&old_path, &new_path)
+                        .context(format!("{old_path:?} 重命名为 {new_path:?} 失败"))?;
+                    renamed += 1;
+                } else if !new_path.exists() {
+                    log::warn!("{} 不存在，仅更新元数据", old_path.display());
+                }
+
+                record.file = new_file;
+            }
+
+            remove_empty_dirs(&task_path);
+        }
+
+        ledger.save(root, owner, repo)?;
+
+        log::info!("{owner}/{repo} 迁移完成");
+    }
+
+    write_version(root, CURRENT_VERSION)?;
+
+    log::info!(
+        "{} 已从 v{version} 迁移到 v{CURRENT_VERSION}，共重命名 {renamed} 个文件",
+        root.display()
+    );
+
+    Ok(())
+}
+
+/// 旧布局里 cursor 带 `/` 时会产生子目录，迁移后把空掉的子目录删掉。
+fn remove_empty_dirs(path: &Path) {
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let sub = entry.path();
+        if sub.is_dir() {
+            remove_empty_dirs(&sub);
+            // 非空目录会删除失败，正好保留下来。
+            let _ = fs::remove_dir(&sub);
+        }
+    }
+}
+
+#[test]
+fn test_cursor_encoding() -> Result<()> {
+    let cursor = Some("Y3Vyc29yOnYyOpK5MjAxOS0w+/MDowMCswODowMM4Fc/Oj==".to_string());
+    let encoded = encode_cursor(&cursor);
+
+    assert!(encoded
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    assert_eq!(decode_cursor(&encoded)?, cursor);
+    assert_eq!(decode_cursor(&encode_cursor(&None))?, None);
+
+    // 超过 999 之后依然按字典序排列
+    assert!(page_file_name(999, &cursor) < page_file_name(1000, &cursor));
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_legacy_tree() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("layout_test_{}", std::process::id()));
+    let task_path = util::task_dir(&root, "owner", "some_repo", TaskType::PRCommits);
+    fs::create_dir_all(task_path.join("001_abc"))?;
+
+    let page = r#"{"repository":{"pullRequests":{"pageInfo":{},"nodes":[{}]}}}"#;
+    fs::write(task_path.join("000_first_cursor.json"), page)?;
+    // 旧布局中带 `/` 的 cursor 会落在子目录里
+    fs::write(task_path.join("001_abc").join("def=.json"), page)?;
+    fs::write(task_path.join("1000_xyz.json"), page)?;
+
+    assert_eq!(read_version(&root)?, 1);
+    assert!(ensure_current(&root).is_err());
+
+    migrate(&root)?;
+
+    assert_eq!(read_version(&root)?, CURRENT_VERSION);
+    ensure_current(&root)?;
+
+    let ledger = RepoLedger::load(&root, "owner", "some_repo")?;
+    let task = ledger.task(TaskType::PRCommits).unwrap();
+    let steps = task.steps.iter().map(|r| r.step).collect::<Vec<_>>();
+    assert_eq!(steps, vec![0, 1, 1000]);
+    assert_eq!(task.steps[1].cursor.as_deref(), Some("abc/def="));
+    assert_eq!(task.resume_point(), (Some(1000), Some("xyz".to_string())));
+
+    for record in &task.steps {
+        assert!(task_path.join(&record.file).is_file());
+    }
+    assert!(!task_path.join("001_abc").exists());
+
+    // 再跑一次不会有任何变化
+    migrate(&root)?;
+
+    fs::remove_dir_all(&root)?;
+
+    Ok(())
+}
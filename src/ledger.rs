@@ -0,0 +1,521 @@
+// 每个仓库一份的采集元数据，记录在 `<root>/<owner>_<repo>/metadata.json`。
+// 续爬、进度查看和校验都从这里读取状态，而不是去解析输出目录里的文件名。
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cost::CostTotals;
+use crate::layout;
+use crate::util::{self, TaskType};
+use crate::window::AdaptiveWindow;
+
+const LEDGER_FILE_NAME: &str = "metadata.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    #[default]
+    Pending,
+    InProgress,
+    /// 已经没有下一页了，后续再跑只会重新请求最后一页。
+    Completed,
+    Failed,
+    /// 仓库无法访问，原因记在 `last_error` 里，下次运行会重新检查一次。
+    Skipped,
+}
+
+impl std::fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            TaskStatus::Pending => write!(f, "pending"),
+            TaskStatus::InProgress => write!(f, "in_progress"),
+            TaskStatus::Completed => write!(f, "completed"),
+            TaskStatus::Failed => write!(f, "failed"),
+            TaskStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// 每一页的采集记录
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub step: i32,
+    /// 请求这一页时使用的 cursor，`None` 表示第一页。
+    pub cursor: Option<String>,
+    /// 这一页返回的 endCursor，也就是下一页的 cursor。
+    pub end_cursor: Option<String>,
+    /// 分页文件名，相对于任务目录，见 `layout::page_file_name`。
+    pub file: String,
+    pub item_count: usize,
+    /// 实际使用的窗口大小，旧数据导入时无法得知。
+    pub window: Option<i64>,
+    pub fetched_at: DateTime<Utc>,
+    /// 请求这一页的消耗，包括补齐内层连接的请求，旧数据没有。
+    #[serde(default)]
+    pub cost: Option<CostTotals>,
+}
+
+/// 增量同步时和已有分页对不上的节点（比如新关闭的旧 issue）单独存一页
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeltaRecord {
+    /// 变更页文件名，相对于任务目录，见 `layout::delta_file_name`。
+    pub file: String,
+    pub item_count: usize,
+    /// 这次同步取的是这个时间之后更新过的节点
+    pub since: DateTime<Utc>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskLedger {
+    pub status: TaskStatus,
+    pub steps: Vec<StepRecord>,
+    pub last_error: Option<String>,
+    /// 已经同步到的最大 updatedAt（服务器时间），增量同步只取这之后更新过的节点。
+    #[serde(default)]
+    pub high_water_mark: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub deltas: Vec<DeltaRecord>,
+    /// 上一次请求之后的窗口大小，续爬时从这里开始。
+    #[serde(default)]
+    pub window: AdaptiveWindow,
+    /// 这个任务所有请求的累计消耗，包括重新请求的页和增量同步。
+    #[serde(default)]
+    pub cost: CostTotals,
+}
+
+impl TaskLedger {
+    pub fn last_step(&self) -> Option<&StepRecord> {
+        self.steps.last()
+    }
+
+    /// 续爬的起点。
+    ///
+    /// 上一次爬虫最后一个请求要重新求，因为新的数据会增长到后面，所以返回的是
+    /// 最后一页自己的 step 和 cursor。
+    pub fn resume_point(&self) -> (Option<i32>, Option<String>) {
+        match self.last_step() {
+            Some(record) => (Some(record.step), record.cursor.clone()),
+            None => (None, None),
+        }
+    }
+
+    /// 增量同步的起点，全量采集还没有完成的任务返回 None，继续按创建时间续爬。
+    ///
+    /// 没完成就改成增量同步的话，还没翻到的旧节点只要之后没有更新过就再也采不到了。
+    /// 已经完成但还没有同步过的，取全量采集中最早一页的时间，那之后的改动都可能没有采到。
+    pub fn sync_since(&self) -> Option<DateTime<Utc>> {
+        if self.high_water_mark.is_some() {
+            return self.high_water_mark;
+        }
+        if self.status != TaskStatus::Completed {
+            return None;
+        }
+        self.steps.iter().map(|r| r.fetched_at).min()
+    }
+
+    /// 所有数据文件，分页在前，变更页在后。
+    pub fn data_files(&self) -> impl Iterator<Item = &str> {
+        self.steps
+            .iter()
+            .map(|r| r.file.as_str())
+            .chain(self.deltas.iter().map(|d| d.file.as_str()))
+    }
+
+    /// 记录一页，重新请求的页会覆盖同 step 的旧记录。
+    pub fn record_step(&mut self, record: StepRecord) {
+        self.steps.retain(|r| r.step < record.step);
+        self.steps.push(record);
+        self.status = TaskStatus::InProgress;
+        self.last_error = None;
+    }
+
+    /// 从最后一页开始往前检查分页文件，丢掉读不出来或者 `is_valid` 不认的页，返回丢掉的页数。
+    ///
+    /// 被丢掉的页的文件会被删除，续爬时从剩下的最后一页的 cursor 重新请求。
+    /// 同时清理上次中断时留下的临时文件。
+    pub fn drop_corrupt_tail(
+        &mut self,
+        task_path: &Path,
+        is_valid: impl Fn(&[u8]) -> bool,
+    ) -> Result<usize> {
+        remove_temp_files(task_path)?;
+
+        let mut dropped = 0;
+        while let Some(record) = self.steps.last() {
+            let page = task_path.join(&record.file);
+            match fs::read(&page) {
+                Ok(bytes) if is_valid(&bytes) => break,
+                result => {
+                    let reason = result.map_or_else(|e| e.to_string(), |_| "内容不完整".into());
+                    log::warn!(
+                        "{} 无法解析（{reason}），丢弃 step {} 之后从上一页的 cursor 继续",
+                        page.display(),
+                        record.step
+                    );
+                    if page.exists() {
+                        fs::remove_file(&page).context(format!("{} 删除失败", page.display()))?;
+                    }
+                    self.steps.pop();
+                    dropped += 1;
+                }
+            }
+        }
+
+        if dropped > 0 && self.status == TaskStatus::Completed {
+            self.status = TaskStatus::InProgress;
+        }
+
+        Ok(dropped)
+    }
+
+    pub fn mark_completed(&mut self) {
+        self.status = TaskStatus::Completed;
+        self.last_error = None;
+    }
+
+    pub fn mark_failed(&mut self, error: String) {
+        self.status = TaskStatus::Failed;
+        self.last_error = Some(error);
+    }
+
+    pub fn mark_skipped(&mut self, reason: String) {
+        self.status = TaskStatus::Skipped;
+        self.last_error = Some(reason);
+    }
+
+    /// 从分页文件名中恢复记录，用于旧布局（`NNN_<cursor>.json`）或者元数据丢失的情况。
+    fn from_pages(task_path: &Path) -> Result<Self> {
+        let mut steps = util::list_pages(task_path)?
+            .into_iter()
+            .filter_map(|file| {
+                let (step_str, cursor) = file.strip_suffix(".json")?.split_once('_')?;
+                let step = step_str.parse::<i32>().ok()?;
+                let cursor = if step_str.len() == 6 {
+                    // 当前布局的文件名，cursor 是编码过的
+                    layout::decode_cursor(cursor).ok()?
+                } else {
+                    // 首个 step 的 cursor 是 first_cursor
+                    (step != 0).then(|| cursor.to_string())
+                };
+
+                let page = task_path.join(&file);
+
+                let item_count = fs::File::open(&page)
+                    .ok()
+                    .and_then(|f| serde_json::from_reader::<_, serde_json::Value>(f).ok())
+                    .and_then(|data| count_nodes(&data))
+                    .unwrap_or(0);
+
+                let fetched_at = fs::metadata(&page)
+                    .and_then(|m| m.modified())
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(|_| Utc::now());
+
+                Some(StepRecord {
+                    step,
+                    cursor,
+                    end_cursor: None,
+                    file,
+                    item_count,
+                    window: None,
+                    fetched_at,
+                    cost: None,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // 三位数的编号超过 999 之后字典序就不对了，按数值重新排一次。
+        steps.sort_by_key(|r| r.step);
+
+        // 旧文件只在 cursor 上记录了下一页的入口，反推上一页的 end_cursor。
+        for i in 1..steps.len() {
+            steps[i - 1].end_cursor = steps[i].cursor.clone();
+        }
+
+        Ok(Self {
+            status: if steps.is_empty() {
+                TaskStatus::Pending
+            } else {
+                TaskStatus::InProgress
+            },
+            steps,
+            ..Default::default()
+        })
+    }
+}
+
+/// 删除任务目录下写了一半的临时文件
+fn remove_temp_files(task_path: &Path) -> Result<()> {
+    let Ok(entries) = fs::read_dir(task_path) else {
+        return Ok(());
+    };
+
+    for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+        if path.is_file()
+            && path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(util::TEMP_SUFFIX))
+        {
+            log::warn!("删除上次中断时留下的临时文件 {}", path.display());
+            fs::remove_file(&path).context(format!("{} 删除失败", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 分页文件里节点的个数，不区分任务类型。
+fn count_nodes(data: &serde_json::Value) -> Option<usize> {
+    data.get("repository")?
+        .as_object()?
+        .values()
+        .find_map(|connection| connection.get("nodes")?.as_array().map(Vec::len))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RepoLedger {
+    /// key 为 `TaskType` 的 Display 名
+    pub tasks: BTreeMap<String, TaskLedger>,
+}
+
+impl RepoLedger {
+    pub fn path(root: &Path, owner: &str, repo: &str) -> PathBuf {
+        root.join(format!("{}_{}", owner, repo))
+            .join(LEDGER_FILE_NAME)
+    }
+
+    /// 读取仓库的元数据；还没有元数据的仓库会尝试从输出文件名中导入一次。
+    pub fn load(root: &Path, owner: &str, repo: &str) -> Result<Self> {
+        let path = Self::path(root, owner, repo);
+
+        if path.exists() {
+            let file = fs::File::open(&path).context(format!("{path:?} 打开失败"))?;
+            return serde_json::from_reader(file).context(format!("{path:?} 解析失败"));
+        }
+
+        let mut ledger = Self::default();
+        for task_type in TaskType::ALL {
+            let task_path = util::task_dir(root, owner, repo, task_type);
+            if !task_path.exists() {
+                continue;
+            }
+            let task = TaskLedger::from_pages(&task_path)?;
+            log::info!(
+                "从文件名导入 {owner}/{repo} 的 {task_type} 状态，共 {} 页",
+                task.steps.len()
+            );
+            ledger.tasks.insert(task_type.to_string(), task);
+        }
+
+        Ok(ledger)
+    }
+
+    pub fn save(&self, root: &Path, owner: &str, repo: &str) -> Result<()> {
+        let path = Self::path(root, owner, repo);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("{parent:?} 路径创建出现问题"))?;
+        }
+
+        let json = serde_json::to_string_pretty(self)?;
+        util::write_atomic(&path, json.as_bytes()).context(format!("{path:?} 写入失败"))
+    }
+
+    pub fn task(&self, task_type: TaskType) -> Option<&TaskLedger> {
+        self.tasks.get(&task_type.to_string())
+    }
+
+    pub fn task_mut(&mut self, task_type: TaskType) -> &mut TaskLedger {
+        self.tasks.entry(task_type.to_string()).or_default()
+    }
+
+    /// 仓库无法访问时把还没完成的任务记为跳过，已经采集完的数据仍然有效，保持完成状态。
+    pub fn mark_unavailable(&mut self, tasks: &[TaskType], reason: &str) {
+        for &task_type in tasks {
+            let task = self.task_mut(task_type);
+            if task.status != TaskStatus::Completed {
+                task.mark_skipped(reason.to_string());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_legacy_import_and_roundtrip() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("ledger_test_{}", std::process::id()));
+    let task_path = util::task_dir(&root, "owner", "repo", TaskType::ClosedIssues);
+    fs::create_dir_all(&task_path)?;
+
+    let page = r#"{"repository":{"issues":{"pageInfo":{},"nodes":[{},{}]}}}"#;
+    fs::write(task_path.join("000_first_cursor.json"), page)?;
+    fs::write(task_path.join("001_Y3Vyc29yOjE=.json"), page)?;
+    fs::write(task_path.join("002_Y3Vyc29yOjI=.json"), page)?;
+
+    let ledger = RepoLedger::load(&root, "owner", "repo")?;
+    let task = ledger.task(TaskType::ClosedIssues).unwrap();
+
+    assert_eq!(task.steps.len(), 3);
+    assert_eq!(task.steps[0].cursor, None);
+    assert_eq!(task.steps[0].end_cursor.as_deref(), Some("Y3Vyc29yOjE="));
+    assert_eq!(task.steps[1].item_count, 2);
+    assert_eq!(
+        task.resume_point(),
+        (Some(2), Some("Y3Vyc29yOjI=".to_string()))
+    );
+
+    ledger.save(&root, "owner", "repo")?;
+    let reloaded = RepoLedger::load(&root, "owner", "repo")?;
+    assert_eq!(
+        reloaded.task(TaskType::ClosedIssues).unwrap().steps,
+        task.steps
+    );
+
+    fs::remove_dir_all(&root)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_current_layout_without_metadata() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("ledger_recover_test_{}", std::process::id()));
+    let task_path = util::task_dir(&root, "owner", "repo", TaskType::Discussions);
+    fs::create_dir_all(&task_path)?;
+
+    let cursor = Some("Y3Vyc29y/Oj+==".to_string());
+    let page = r#"{"repository":{"discussions":{"pageInfo":{},"nodes":[{}]}}}"#;
+    fs::write(task_path.join(layout::page_file_name(0, &None)), page)?;
+    fs::write(task_path.join(layout::page_file_name(1, &cursor)), page)?;
+
+    let ledger = RepoLedger::load(&root, "owner", "repo")?;
+    let task = ledger.task(TaskType::Discussions).unwrap();
+
+    assert_eq!(task.resume_point(), (Some(1), cursor));
+
+    fs::remove_dir_all(&root)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_record_step_overwrites_refetched_page() {
+    let record = |step: i32| StepRecord {
+        step,
+        cursor: Some(format!("c{step}")),
+        end_cursor: Some(format!("c{}", step + 1)),
+        file: format!("{step:03}.json"),
+        item_count: 100,
+        window: Some(100),
+        fetched_at: Utc::now(),
+        cost: None,
+    };
+
+    let mut task = TaskLedger::default();
+    task.record_step(record(0));
+    task.record_step(record(1));
+    task.record_step(record(1));
+
+    assert_eq!(task.steps.len(), 2);
+    assert_eq!(task.status, TaskStatus::InProgress);
+    assert_eq!(task.resume_point(), (Some(1), Some("c1".to_string())));
+}
+
+#[test]
+fn test_sync_since_only_after_full_crawl() {
+    let fetched_at = Utc::now() - chrono::Duration::days(1);
+    let mut task = TaskLedger::default();
+    task.record_step(StepRecord {
+        step: 0,
+        cursor: None,
+        end_cursor: Some("c1".to_string()),
+        file: "000.json".to_string(),
+        item_count: 100,
+        window: Some(100),
+        fetched_at,
+        cost: None,
+    });
+
+    // 还有下一页没采的任务继续全量续爬，不能改成增量同步。
+    assert_eq!(task.sync_since(), None);
+    task.mark_failed("timeout".to_string());
+    assert_eq!(task.sync_since(), None);
+
+    task.mark_completed();
+    assert_eq!(task.sync_since(), Some(fetched_at));
+
+    // 同步过一次之后以高水位为准
+    let mark = Utc::now();
+    task.high_water_mark = Some(mark);
+    task.mark_failed("timeout".to_string());
+    assert_eq!(task.sync_since(), Some(mark));
+}
+
+#[test]
+fn test_drop_corrupt_tail() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("ledger_corrupt_test_{}", std::process::id()));
+    let task_path = util::task_dir(&root, "owner", "repo", TaskType::ClosedIssues);
+    fs::create_dir_all(&task_path)?;
+
+    let record = |step: i32| StepRecord {
+        step,
+        cursor: (step > 0).then(|| format!("c{step}")),
+        end_cursor: Some(format!("c{}", step + 1)),
+        file: format!("{step:03}.json"),
+        item_count: 1,
+        window: Some(100),
+        fetched_at: Utc::now(),
+        cost: None,
+    };
+
+    let mut task = TaskLedger::default();
+    for step in 0..3 {
+        task.record_step(record(step));
+    }
+    task.mark_completed();
+
+    fs::write(task_path.join("000.json"), "{}")?;
+    fs::write(task_path.join("001.json"), "{}")?;
+    // 最后一页写了一半
+    fs::write(task_path.join("002.json"), "{\"repository\":{\"iss")?;
+    fs::write(task_path.join("003.json.tmp"), "{")?;
+
+    let is_valid = |bytes: &[u8]| serde_json::from_slice::<serde_json::Value>(bytes).is_ok();
+    assert_eq!(task.drop_corrupt_tail(&task_path, is_valid)?, 1);
+    assert_eq!(task.resume_point(), (Some(1), Some("c1".to_string())));
+    assert_eq!(task.status, TaskStatus::InProgress);
+    assert!(!task_path.join("002.json").exists());
+    assert!(!task_path.join("003.json.tmp").exists());
+
+    // 完好的页不受影响
+    assert_eq!(task.drop_corrupt_tail(&task_path, is_valid)?, 0);
+    assert_eq!(task.steps.len(), 2);
+
+    fs::remove_dir_all(&root)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_unavailable_keeps_completed_tasks() {
+    let mut ledger = RepoLedger::default();
+    ledger.task_mut(TaskType::Discussions).mark_completed();
+    ledger
+        .task_mut(TaskType::ClosedIssues)
+        .mark_failed("502".to_string());
+
+    ledger.mark_unavailable(&TaskType::ALL, "NOT_FOUND");
+
+    let discussions = ledger.task(TaskType::Discussions).unwrap();
+    assert_eq!(discussions.status, TaskStatus::Completed);
+    assert_eq!(discussions.last_error, None);
+    for task_type in [TaskType::ClosedIssues, TaskType::PRCommits] {
+        let task = ledger.task(task_type).unwrap();
+        assert_eq!(task.status, TaskStatus::Skipped);
+        assert_eq!(task.last_error.as_deref(), Some("NOT_FOUND"));
+    }
+}
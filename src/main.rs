@@ -1,200 +1,569 @@
+mod cli;
+mod commands;
 mod config;
+mod cost;
+mod dead_letter;
+mod error;
+mod estimate;
+mod governor;
 mod graphql_client_ext;
+mod incremental;
+mod instance_lock;
+mod layout;
+mod ledger;
+mod metrics;
+mod nested;
+mod progress;
 mod query;
+mod retry;
+mod schedule;
+mod shared_limits;
+mod shutdown;
+mod supervise;
+mod token_pool;
 mod util;
+mod window;
 
 use anyhow::{Context, Ok, Result};
+use clap::Parser;
+use dead_letter::DeadLetterQueue;
+use governor::Governor;
+use graphql_client_ext::GithubClient;
+use instance_lock::InstanceLock;
+use ledger::{RepoLedger, StepRecord, TaskLedger};
+use progress::Progress;
+use query::{PaginatedTask, QueryOrder};
 use reqwest::{blocking, header};
-use std::fs::File;
-use std::io::{self, BufRead};
+use shared_limits::SharedRateLimits;
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use token_pool::TokenPool;
 use util::TaskType;
 
-// 每个仓库每个类型的数据采集步数的上限
-const STEP_THRESHHOLD: i32 = 5;
-
-// 重试间隔时间，单位秒
-const BASE_RETRY_SECS: u64 = 5;
-
 fn main() -> Result<()> {
-    // log::set_logger(&log::MY_LOGGER).expect("logger init failed");
-    // log::set_max_level(log::LevelFilter::Info);
+    let cli = cli::Cli::parse();
 
-    log4rs::init_file("config/log4rs.yaml", Default::default())?;
+    log4rs::init_file(&cli.log_config, Default::default())
+        .context(format!("{} 日志配置加载失败", cli.log_config.display()))?;
+
+    shutdown::install()?;
 
     log::info!("begin");
 
-    // 读取配置构建 reqwest client
-    let config::Config { token, user_agent } = config::load()?;
+    if let Err(e) = run(&cli) {
+        // 主动停止用单独的退出码，守护脚本据此区分手动停止和崩溃。
+        if error::is_interrupted(&e) {
+            log::warn!("已停止，进度已保存，再次运行同样的命令会从断点继续");
+            std::process::exit(shutdown::EXIT_CODE);
+        }
+        if e.downcast_ref::<error::FinishedWithFailures>().is_some() {
+            log::error!("{e}");
+            eprintln!("Error: {e:?}");
+            std::process::exit(error::FAILURES_EXIT_CODE);
+        }
+        return Err(e);
+    }
+
+    log::info!("end");
+
+    Ok(())
+}
+
+fn run(cli: &cli::Cli) -> Result<()> {
+    use cli::Command;
+    match &cli.command {
+        Command::Crawl(args) => crawl(args)?,
+        Command::RetryFailed(args) => retry_failed(args)?,
+        Command::Status(args) => commands::status(args)?,
+        Command::Cost(args) => commands::cost(args)?,
+        Command::Export(args) => commands::export(args)?,
+        Command::Verify(args) => commands::verify(args)?,
+        Command::Query(args) => commands::query(args)?,
+        Command::Migrate(args) => {
+            let _lock = InstanceLock::acquire(&args.output)?;
+            layout::migrate(&args.output)?
+        }
+        Command::Supervise(args) => supervise::run(&cli.log_config, args)?,
+    }
 
-    let client = blocking::Client::builder()
-        .default_headers(header::HeaderMap::from_iter([
-            (header::AUTHORIZATION, format!("bearer {token}").parse()?),
-            (header::USER_AGENT, user_agent.parse()?),
-        ]))
+    Ok(())
+}
+
+/// 读取配置构建 reqwest client
+fn build_client(config_path: &Path) -> Result<GithubClient> {
+    let config = config::load(config_path)?;
+    let tokens = config.all_tokens();
+
+    let http = blocking::Client::builder()
+        // token 由 GithubClient 在每次请求时选择
+        .default_headers(header::HeaderMap::from_iter([(
+            header::USER_AGENT,
+            config.user_agent.parse()?,
+        )]))
         // https_only，似乎不选择协议的话，客户端还是会按默认 http。（不明）
         .https_only(true)
         .build()?;
 
-    log::info!("client built");
+    log::info!(
+        "client built with {} token(s), reserve: {:?}",
+        tokens.len(),
+        config.reserve
+    );
+
+    Ok(GithubClient::new(
+        http,
+        TokenPool::new(tokens, config.reserve),
+        Governor::new(config.governor),
+        config.retry,
+        SharedRateLimits::new(config.shared_state),
+    ))
+}
 
-    let file = File::open("repolist.txt").context("没有找到 repolist.txt")?;
+/// 一次采集运行中所有 worker 共享的状态
+struct CrawlContext<'a> {
+    root: &'a Path,
+    client: &'a GithubClient,
+    step_limit: i32,
+    incremental: bool,
+    dead_letter: &'a DeadLetterQueue,
+    progress: &'a Progress,
+}
 
-    io::BufReader::new(file)
-        .lines()
-        .map_while(Result::ok)
-        .filter_map(|line| {
-            if line.starts_with('#') {
-                return None;
+/// 一个仓库和它需要采集的任务
+type RepoJob = (String, String, Vec<TaskType>);
+
+fn crawl(
+    cli::CrawlArgs {
+        common: args,
+        workers,
+        incremental,
+        dry_run,
+        metrics_addr,
+    }: &cli::CrawlArgs,
+) -> Result<()> {
+    if *dry_run {
+        return estimate::run(args);
+    }
+
+    let root = args.output.as_path();
+
+    let _lock = InstanceLock::acquire(root)?;
+    layout::ensure_current(root)?;
+
+    if let Some(addr) = metrics_addr {
+        metrics::serve(*addr)?;
+    }
+
+    let client = build_client(&args.config)?;
+    let dead_letter = DeadLetterQueue::load(root)?;
+    let progress = Progress::new(root, "crawl", args.step_limit);
+
+    let ctx = CrawlContext {
+        root,
+        client: &client,
+        step_limit: args.step_limit,
+        incremental: *incremental,
+        dead_letter: &dead_letter,
+        progress: &progress,
+    };
+
+    let jobs = util::read_repo_list(&args.repo_list)?
+        .into_iter()
+        .map(|(repo_owner, repo_name)| (repo_owner, repo_name, args.tasks.clone()))
+        .collect();
+
+    let result = run_workers(&ctx, jobs, *workers);
+    progress.finish(&client, &result);
+
+    let failed = result?;
+    if !failed.is_empty() {
+        return Err(error::FinishedWithFailures(format!(
+            "{} 个仓库采集失败（{}），失败原因见 `status`，可以用 `retry-failed` 重试",
+            failed.len(),
+            failed.join(", ")
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// 只重跑失败列表里的任务，每一轮之间按指数退避等待。
+fn retry_failed(args: &cli::RetryArgs) -> Result<()> {
+    let root = args.common.output.as_path();
+
+    let _lock = InstanceLock::acquire(root)?;
+    layout::ensure_current(root)?;
+
+    if let Some(addr) = args.metrics_addr {
+        metrics::serve(addr)?;
+    }
+
+    let client = build_client(&args.common.config)?;
+    let dead_letter = DeadLetterQueue::load(root)?;
+    let progress = Progress::new(root, "retry-failed", args.common.step_limit);
+
+    let ctx = CrawlContext {
+        root,
+        client: &client,
+        step_limit: args.common.step_limit,
+        incremental: args.incremental,
+        dead_letter: &dead_letter,
+        progress: &progress,
+    };
+
+    let result = retry_passes(&ctx, args);
+    progress.finish(&client, &result);
+    result?;
+
+    let remaining = dead_letter
+        .entries()
+        .into_iter()
+        .filter(|entry| args.common.tasks.contains(&entry.task))
+        .count();
+    if remaining > 0 {
+        return Err(error::FinishedWithFailures(format!(
+            "仍有 {remaining} 个任务失败，详见 dead_letter.json"
+        ))
+        .into());
+    }
+
+    log::info!("失败列表已清空");
+
+    Ok(())
+}
+
+/// 按 `--passes` 重跑失败列表，直到清空或者轮数用完。
+fn retry_passes(ctx: &CrawlContext, args: &cli::RetryArgs) -> Result<()> {
+    let dead_letter = ctx.dead_letter;
+
+    for pass in 0..args.passes {
+        // 同一个仓库的失败任务合并成一个 job，保持失败列表里的顺序。
+        let mut jobs: Vec<RepoJob> = Vec::new();
+        for entry in dead_letter.entries() {
+            if !args.common.tasks.contains(&entry.task) {
+                continue;
             }
-            let mut it = line.split('/');
-            let repo_owner = it.next()?.to_string();
-            let repo_name = it.next()?.to_string();
-            Some((repo_owner, repo_name))
-        })
-        .enumerate()
-        // 采集任务主体：遍历仓库列表，采集每个仓库的讨论区。
-        .try_for_each(|(i, (repo_owner, repo_name))| {
-            log::info!("[line: {i}] crawling {repo_owner}/{repo_name}");
-
-            for task_type in [
-                TaskType::Discussions,
-                TaskType::PRCommits,
-                TaskType::ClosedIssues,
-            ] {
-                log::info!("正在采集的目标为 {repo_owner}/{repo_name} 的 {task_type}");
-
-                //  检查对应的文件是否存在
-                let (last_step, last_cursor) = read_state(&repo_owner, &repo_name, task_type)
-                    .unwrap_or(/* 不管如何报错都当空的 */ (None, None));
-
-                if last_step >= Some(STEP_THRESHHOLD) {
-                    log::info!(
-                        "已经采集到最大步数 `STEP_THRESHHOLD: {STEP_THRESHHOLD}`，跳过 {repo_owner}/{repo_name} 的 {task_type}",
-                        repo_owner = repo_owner,
-                        repo_name = repo_name,
-                        task_type = task_type
-                    );
-                    continue;
-                } else {
-                    log::info!(
-                        "读取到状态 last_step: {:?}, last_cursor: {:?}",
-                        last_step, last_cursor
-                    );
-                }
-
-                crawling(
-                    &repo_owner,
-                    &repo_name,
-                    &client,
-                    task_type,
-                    last_step,
-                    last_cursor,
-                )?;
+            if entry.attempts >= args.max_attempts {
+                log::warn!(
+                    "{}/{} 的 {} 已经失败 {} 次，不再重试：{}",
+                    entry.repo_owner,
+                    entry.repo_name,
+                    entry.task,
+                    entry.attempts,
+                    entry.last_error
+                );
+                continue;
             }
-            Ok(())
-        })?;
 
-    log::info!("end");
+            match jobs
+                .iter_mut()
+                .find(|(owner, name, _)| *owner == entry.repo_owner && *name == entry.repo_name)
+            {
+                Some((_, _, tasks)) => tasks.push(entry.task),
+                None => jobs.push((entry.repo_owner, entry.repo_name, vec![entry.task])),
+            }
+        }
+
+        if jobs.is_empty() {
+            break;
+        }
+
+        if pass > 0 {
+            let backoff_secs = args.backoff_secs << (pass - 1);
+            log::info!("第 {pass} 轮重试之后仍有失败，{backoff_secs}s 后开始下一轮");
+            let backoff = std::time::Duration::from_secs(backoff_secs);
+            let _backoff = ctx
+                .client
+                .activity
+                .backing_off(format!("第 {pass} 轮重试之后仍有失败"), backoff);
+            ctx.progress.update(ctx.client);
+            if !shutdown::sleep(backoff) {
+                return Err(error::Error::Interrupted.into());
+            }
+        }
+
+        log::info!("第 {} 轮重试，共 {} 个仓库", pass + 1, jobs.len());
+
+        if run_workers(ctx, jobs, args.workers)?.is_empty() {
+            break;
+        }
+    }
 
     Ok(())
 }
 
-fn read_state(
+/// 多个 worker 从同一个队列里领取仓库，同一个仓库的各类任务由同一个 worker
+/// 顺序完成，这样每个仓库的元数据只会被一个线程写。
+///
+/// 单个仓库失败时失败原因已经记进元数据和失败列表，继续下一个仓库，最后返回失败的仓库；
+/// 只有致命错误才中止整次运行。
+fn run_workers(ctx: &CrawlContext, jobs: Vec<RepoJob>, workers: usize) -> Result<Vec<String>> {
+    ctx.progress.begin_batch(ctx.client, jobs.len());
+
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let failed: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let fatal_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+
+    thread::scope(|s| {
+        // 等待重置或者退避的时候没有新页，状态文件另外定时刷新，所有 worker 结束后停止。
+        let (stop_heartbeat, heartbeat_stopped) = mpsc::channel::<()>();
+        s.spawn(move || ctx.progress.heartbeat(ctx.client, heartbeat_stopped));
+
+        let mut handles = Vec::new();
+        for worker in 0..workers.max(1) {
+            let (queue, failed, fatal_error) = (&queue, &failed, &fatal_error);
+            let handle = thread::Builder::new()
+                .name(format!("worker-{worker}"))
+                .spawn_scoped(s, move || loop {
+                    // 出现致命错误或者收到退出信号之后不再领取新仓库
+                    if fatal_error.lock().unwrap().is_some() || shutdown::requested() {
+                        break;
+                    }
+
+                    let Some((i, (repo_owner, repo_name, tasks))) = queue.lock().unwrap().next()
+                    else {
+                        break;
+                    };
+
+                    log::info!("[line: {i}] crawling {repo_owner}/{repo_name}");
+
+                    let Err(e) = crawl_repo(ctx, &repo_owner, &repo_name, &tasks) else {
+                        ctx.progress.repo_finished(ctx.client, true);
+                        continue;
+                    };
+
+                    if error::is_fatal(&e) {
+                        fatal_error.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+
+                    log::error!("{repo_owner}/{repo_name} 采集失败，继续下一个仓库：{e:#}");
+                    ctx.progress.repo_finished(ctx.client, false);
+                    failed
+                        .lock()
+                        .unwrap()
+                        .push(format!("{repo_owner}/{repo_name}"));
+                })
+                .expect("worker 线程创建失败");
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        drop(stop_heartbeat);
+    });
+
+    if let Some(e) = fatal_error.into_inner().unwrap() {
+        return Err(e);
+    }
+    // 信号刚好落在两个仓库之间时没有 worker 返回错误
+    if shutdown::requested() {
+        return Err(error::Error::Interrupted.into());
+    }
+
+    Ok(failed.into_inner().unwrap())
+}
+
+/// 顺序采集一个仓库的指定任务
+fn crawl_repo(
+    ctx: &CrawlContext,
     repo_owner: &str,
     repo_name: &str,
-    task_type: TaskType,
-) -> Result<(Option<i32>, Option<String>)> {
-    // TODO 虽然这样的设计也算是可以解决问题，但为了更长远的考虑，最好每个仓库建
-    // 立一个 metadata 专门保留所有的状态数据
-
-    use std::path::Path;
-
-    let task_path = Path::new("output")
-        .join(format!("{}_{}", repo_owner, repo_name))
-        .join(task_type.to_string());
-
-    //  这里直接用 last 检查最后一个文件，原因是编号体系保证顺序最后一个是最新的。
-    //  从文件名中提取出 step & cursor
-    let last = std::fs::read_dir(task_path)
-        .context("文件夹不存在")?
-        .filter_map(Result::ok)
-        .map(|e| e.path())
-        .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "json"))
-        .last()
-        .context("未能找到已有历史")?;
-
-    let (last_step, last_cursor) = last
-        .file_name()
-        .and_then(|name| name.to_str())
-        .and_then(|name| name.strip_suffix(".json"))
-        .map(|name| name.split('_'))
-        .and_then(|mut splits| {
-            let this_step = splits.next();
-            let this_cursor = splits.next();
-            if let (Some(this_step), Some(this_cursor)) = (this_step, this_cursor) {
-                let step = this_step.parse::<i32>().ok();
-                if step == Some(0) {
-                    // 首个 step 的 cursor 是 first_cursor
-                    return None;
-                }
-                return step.zip(Some(this_cursor.to_string()));
+    tasks: &[TaskType],
+) -> Result<()> {
+    let CrawlContext {
+        root,
+        client,
+        step_limit,
+        incremental,
+        dead_letter,
+        progress,
+    } = *ctx;
+
+    let mut ledger = RepoLedger::load(root, repo_owner, repo_name)?;
+
+    for &task_type in tasks {
+        if shutdown::requested() {
+            return Err(error::Error::Interrupted.into());
+        }
+
+        log::info!("正在采集的目标为 {repo_owner}/{repo_name} 的 {task_type}");
+        progress.at(client, repo_owner, repo_name, task_type, None);
+        if let Some(task) = ledger.task(task_type) {
+            metrics::observe_task(repo_owner, repo_name, task_type, task);
+        }
+
+        // 增量模式下，已经全量采集完的任务只同步更新过的节点，没采过或者没采完的照常全量续爬。
+        let since = incremental
+            .then(|| ledger.task(task_type).and_then(TaskLedger::sync_since))
+            .flatten();
+
+        let result = if let Some(since) = since {
+            query::for_task_type!(task_type, T => incremental::sync::<T>(
+                root,
+                repo_owner,
+                repo_name,
+                client,
+                &mut ledger,
+                since,
+                step_limit,
+                progress,
+            ))
+        } else {
+            //  从元数据中读取续爬的位置
+            let (last_step, last_cursor) = ledger
+                .task(task_type)
+                .map(TaskLedger::resume_point)
+                .unwrap_or((None, None));
+
+            if last_step >= Some(step_limit) {
+                log::info!(
+                    "已经采集到最大步数 `step_limit: {step_limit}`，跳过 {repo_owner}/{repo_name} 的 {task_type}",
+                    repo_owner = repo_owner,
+                    repo_name = repo_name,
+                    task_type = task_type
+                );
+                dead_letter.resolve(repo_owner, repo_name, task_type)?;
+                continue;
+            } else {
+                log::info!(
+                    "读取到状态 last_step: {:?}, last_cursor: {:?}",
+                    last_step,
+                    last_cursor
+                );
+            }
+
+            query::for_task_type!(task_type, T => crawling::<T>(
+                root,
+                repo_owner,
+                repo_name,
+                client,
+                &mut ledger,
+                step_limit,
+                progress,
+            ))
+        };
+
+        let Err(e) = result else {
+            dead_letter.resolve(repo_owner, repo_name, task_type)?;
+            continue;
+        };
+
+        // 主动停止不算失败：已经保存的页都在元数据里，下次从最后一页继续。
+        if error::is_interrupted(&e) {
+            ledger.save(root, repo_owner, repo_name)?;
+            let resume = ledger.task(task_type).and_then(TaskLedger::last_step);
+            log::warn!(
+                "{repo_owner}/{repo_name} 的 {task_type} 已中断，保存到 {}，下次从这里继续",
+                resume.map_or("第一页之前".to_string(), |r| format!(
+                    "step {}（cursor: {:?}）",
+                    r.step, r.end_cursor
+                ))
+            );
+            return Err(e);
+        }
+
+        // 仓库已经无法访问时，没完成的任务都记为跳过，不影响其他仓库，重试也没有意义。
+        if let Some(error::Error::RepoUnavailable(reason)) = e.downcast_ref() {
+            log::warn!("跳过 {repo_owner}/{repo_name}：{reason}");
+            ledger.mark_unavailable(tasks, reason);
+            for &task_type in tasks {
+                dead_letter.resolve(repo_owner, repo_name, task_type)?;
             }
-            None
-        })
-        .context("最后一个文件解析失败（原因可能复杂）")?;
+            return ledger.save(root, repo_owner, repo_name);
+        }
+
+        ledger.task_mut(task_type).mark_failed(format!("{e:#}"));
+        ledger.save(root, repo_owner, repo_name)?;
+        dead_letter.record_failure(repo_owner, repo_name, task_type, format!("{e:#}"))?;
 
-    Ok((Some(last_step), Some(last_cursor)))
+        return Err(e);
+    }
+
+    Ok(())
 }
 
-fn crawling(
+fn crawling<T: PaginatedTask>(
+    root: &Path,
     repo_owner: &str,
     repo_name: &str,
-    client: &blocking::Client,
-    task_type: TaskType,
-    last_step: Option<i32>,
-    last_cursor: Option<String>,
+    client: &GithubClient,
+    ledger: &mut RepoLedger,
+    step_limit: i32,
+    progress: &Progress,
 ) -> Result<()> {
+    let task_type = T::TASK_TYPE;
+
+    // 上次被杀掉时最后一页可能没写完，续爬之前确认它能解析，不能的话退回上一页。
+    if ledger.task(task_type).is_some() {
+        let task_path = util::task_dir(root, repo_owner, repo_name, task_type);
+        let dropped = ledger
+            .task_mut(task_type)
+            .drop_corrupt_tail(&task_path, query::is_valid_page::<T>)?;
+        if dropped > 0 {
+            ledger.save(root, repo_owner, repo_name)?;
+        }
+    }
+
+    let (last_step, last_cursor) = ledger
+        .task(task_type)
+        .map(TaskLedger::resume_point)
+        .unwrap_or((None, None));
+
     let mut cursor: Option<String> = last_cursor;
 
+    let mut adaptive_window = ledger
+        .task(task_type)
+        .map(|task| task.window)
+        .unwrap_or_default();
+
     // 上一次爬虫最后一个请求要重新求，因为新的数据会增长到后面，每一批 100 个节点不一定都在
     let begining_step = last_step.unwrap_or(0);
 
-    for i in begining_step..STEP_THRESHHOLD {
-        // 静态分发调用函数。
+    for i in begining_step..step_limit {
+        // 上一页已经写完并保存，在这里停下不会留下半页。
+        if shutdown::requested() {
+            return Err(error::Error::Interrupted.into());
+        }
+
+        let result = query::single_query::<T>(
+            repo_owner,
+            repo_name,
+            &cursor,
+            QueryOrder::CreatedAsc,
+            &mut adaptive_window,
+            client,
+        );
+        // 失败时同样记下窗口，crawl_repo 记录失败时会一起保存。
+        ledger.task_mut(task_type).window = adaptive_window;
         let query::QueryResult {
             is_empty_page,
+            item_count,
+            window,
             has_next_page,
-            rate_limit,
             query_cursor,
-            response_data: query_response_data,
-        } = match task_type {
-            TaskType::Discussions => {
-                query::single_discussion_query(repo_owner, repo_name, &cursor, client)?
-            }
-            TaskType::PRCommits => {
-                query::single_pr_commits_query(repo_owner, repo_name, &cursor, client)?
-            }
-            TaskType::ClosedIssues => {
-                query::single_issues_query(repo_owner, repo_name, &cursor, client)?
-            }
-        };
+            response_data,
+            cost,
+        } = result?;
+
+        ledger.task_mut(task_type).cost += cost;
 
         // 如果是空页，就不用再继续了。
         if is_empty_page {
             log::info!("{repo_owner}/{repo_name} is_empty_page: true");
+            let task = ledger.task_mut(task_type);
+            task.mark_completed();
+            metrics::observe_task(repo_owner, repo_name, task_type, task);
+            ledger.save(root, repo_owner, repo_name)?;
             break;
         }
 
-        let parsed_json = {
-            // 静态分发序列化函数
-            // 表面上看起来都一样，实际上每个 data 类型都不同。
-            use query::QueryResponseData::*;
-            match query_response_data {
-                Discussions(response_data) => serde_json::to_string(&response_data)?,
-                PRCommits(response_data) => serde_json::to_string(&response_data)?,
-                ClosedIssues(response_data) => serde_json::to_string(&response_data)?,
-            }
-        };
+        let parsed_json = serde_json::to_string(&response_data)?;
 
         log::info!(
             "[{task_type}] [{repo_owner}] [{repo_name}] step {i:03} parsed_json length: {}",
@@ -202,10 +571,34 @@ fn crawling(
         );
 
         // 写入文件还是用的老 cursor，拿这个 Option string 没办法。
-        util::dump_output(&parsed_json, repo_owner, repo_name, task_type, &cursor, i)?;
-
-        // 检查 rate limit 是否超速
-        util::check_limit_and_block(rate_limit);
+        let file = util::dump_output(
+            root,
+            &parsed_json,
+            repo_owner,
+            repo_name,
+            task_type,
+            &cursor,
+            i,
+        )?;
+        metrics::page_written(task_type, item_count, parsed_json.len());
+
+        let task = ledger.task_mut(task_type);
+        task.record_step(StepRecord {
+            step: i,
+            cursor: cursor.clone(),
+            end_cursor: query_cursor.clone(),
+            file,
+            item_count,
+            window: Some(window),
+            fetched_at: chrono::Utc::now(),
+            cost: Some(cost),
+        });
+        if !has_next_page {
+            task.mark_completed();
+        }
+        metrics::observe_task(repo_owner, repo_name, task_type, task);
+        ledger.save(root, repo_owner, repo_name)?;
+        progress.at(client, repo_owner, repo_name, task_type, Some(i));
 
         // 如果没有下一页，就不用再继续了。
         if !has_next_page {
@@ -223,22 +616,26 @@ fn crawling(
 #[test]
 fn test_read_dir() -> Result<()> {
     use std::fs;
-    use std::path::Path;
 
-    let task_path = Path::new("output")
-        .join(format!("{}_{}", "AleoHQ", "leo"))
-        .join(TaskType::ClosedIssues.to_string());
+    let root = std::env::temp_dir().join(format!("read_dir_test_{}", std::process::id()));
+    let task_path = util::task_dir(&root, "AleoHQ", "leo", TaskType::ClosedIssues);
+    fs::create_dir_all(&task_path)?;
 
-    // 也就是说 fs read_dir 有能力拿到文件列表最后一个文件。
-    // 但是前提条件是文件按照字典序排列吧。
-    // TODO 目前文件命名上，每个文件的数字保留位数只有 3，一旦文件数量超过四位数就会有问题。
-    // 目前最大的规模来自 nixos，1456 份让他也采集不完。
-    dbg!(fs::read_dir(task_path)?.last());
+    // 旧的输出目录只有文件名，没有元数据，第一次读取时会从文件名导入。
+    let page = r#"{"repository":{"issues":{"pageInfo":{},"nodes":[]}}}"#;
+    fs::write(task_path.join("000_first_cursor.json"), page)?;
+    fs::write(task_path.join("001_Y3Vyc29yOjE=.json"), page)?;
 
     //  检查对应的文件是否存在
-    let (last_step, _) = read_state("AleoHQ", "leo", TaskType::ClosedIssues)?;
+    let (last_step, last_cursor) = RepoLedger::load(&root, "AleoHQ", "leo")?
+        .task(TaskType::ClosedIssues)
+        .context("未能找到已有历史")?
+        .resume_point();
+
+    assert_eq!(last_step, Some(1));
+    assert_eq!(last_cursor.as_deref(), Some("Y3Vyc29yOjE="));
 
-    assert!(last_step.is_some());
+    fs::remove_dir_all(&root)?;
 
     Ok(())
 }
@@ -0,0 +1,275 @@
+// Prometheus 指标。
+//
+// 长时间无人值守运行时，除了翻 `log/requests.log` 没有别的办法知道采集到了哪里。
+// crawl / retry-failed 带上 `--metrics-addr 127.0.0.1:9898` 时在这个地址上提供 `/metrics`，
+// 按 Prometheus 的文本格式输出请求、重试、窗口缩小、额度、写入量和每个仓库的进度。
+//
+// 指标记在一个全局的表里，请求链路和采集循环在各自的位置直接更新，不用层层传递。
+// 没有开启时只是多维护一张表，不影响采集。
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::ledger::{TaskLedger, TaskStatus};
+use crate::util::TaskType;
+
+const PREFIX: &str = "graphql_github";
+
+/// 读取请求的超时，避免一个不发数据的连接卡住整个服务。
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Metric {
+    Requests,
+    Retries,
+    WindowShrinks,
+    RateLimitLimit,
+    RateLimitRemaining,
+    RateLimitReset,
+    PagesWritten,
+    ItemsWritten,
+    BytesWritten,
+    Repos,
+    RepoPages,
+    RepoItems,
+    RepoCompleted,
+}
+
+impl Metric {
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Requests => "requests_total",
+            Metric::Retries => "retries_total",
+            Metric::WindowShrinks => "window_shrinks_total",
+            Metric::RateLimitLimit => "rate_limit_limit",
+            Metric::RateLimitRemaining => "rate_limit_remaining",
+            Metric::RateLimitReset => "rate_limit_reset_timestamp_seconds",
+            Metric::PagesWritten => "pages_written_total",
+            Metric::ItemsWritten => "items_written_total",
+            Metric::BytesWritten => "bytes_written_total",
+            Metric::Repos => "repos",
+            Metric::RepoPages => "repo_pages",
+            Metric::RepoItems => "repo_items",
+            Metric::RepoCompleted => "repo_completed",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Metric::Requests => "发出的 GraphQL 请求，按响应码区分，连接失败为 error。",
+            Metric::Retries => "请求失败之后的重试，按原因区分。",
+            Metric::WindowShrinks => "窗口缩小的次数，502/504 为 gateway，请求太慢为 slow。",
+            Metric::RateLimitLimit => "每个 token 每小时的额度",
+            Metric::RateLimitRemaining => "每个 token 剩余的额度",
+            Metric::RateLimitReset => "每个 token 的额度重置时间（unix 秒）",
+            Metric::PagesWritten => {
+                "写入的分页文件数，增量同步改写的分页和新增的变更页也计算在内。"
+            }
+            Metric::ItemsWritten => "写入的节点数",
+            Metric::BytesWritten => "写入的分页文件字节数",
+            Metric::Repos => "本批仓库的处理情况",
+            Metric::RepoPages => "每个仓库每类任务已经采集的页数",
+            Metric::RepoItems => "每个仓库每类任务已经采集的节点数",
+            Metric::RepoCompleted => "每个仓库每类任务是否已经采集完成",
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Requests
+            | Metric::Retries
+            | Metric::WindowShrinks
+            | Metric::PagesWritten
+            | Metric::ItemsWritten
+            | Metric::BytesWritten => "counter",
+            _ => "gauge",
+        }
+    }
+}
+
+/// 指标名 -> 标签 -> 数值，标签已经按输出格式拼好。
+type Series = BTreeMap<Metric, BTreeMap<String, f64>>;
+
+static REGISTRY: Mutex<Series> = Mutex::new(BTreeMap::new());
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{pairs}}}")
+}
+
+fn update(series: &mut Series, metric: Metric, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+    f(series
+        .entry(metric)
+        .or_default()
+        .entry(format_labels(labels))
+        .or_default());
+}
+
+/// 计数器加上 `by`
+pub fn inc(metric: Metric, labels: &[(&str, &str)], by: f64) {
+    update(&mut REGISTRY.lock().unwrap(), metric, labels, |v| *v += by);
+}
+
+/// 设置仪表的值
+pub fn set(metric: Metric, labels: &[(&str, &str)], value: f64) {
+    update(&mut REGISTRY.lock().unwrap(), metric, labels, |v| {
+        *v = value
+    });
+}
+
+/// 写完一页（或者增量同步改写了一页）
+pub fn page_written(task_type: TaskType, items: usize, bytes: usize) {
+    let task = task_type.to_string();
+    let labels = [("task", task.as_str())];
+
+    let mut series = REGISTRY.lock().unwrap();
+    update(&mut series, Metric::PagesWritten, &labels, |v| *v += 1.0);
+    update(&mut series, Metric::ItemsWritten, &labels, |v| {
+        *v += items as f64
+    });
+    update(&mut series, Metric::BytesWritten, &labels, |v| {
+        *v += bytes as f64
+    });
+}
+
+/// 按元数据更新一个仓库一类任务的进度
+pub fn observe_task(repo_owner: &str, repo_name: &str, task_type: TaskType, task: &TaskLedger) {
+    let repo = format!("{repo_owner}/{repo_name}");
+    let task_name = task_type.to_string();
+    let labels = [("repo", repo.as_str()), ("task", task_name.as_str())];
+
+    let items: usize = task.steps.iter().map(|r| r.item_count).sum::<usize>()
+        + task.deltas.iter().map(|d| d.item_count).sum::<usize>();
+    let completed = matches!(task.status, TaskStatus::Completed);
+
+    let mut series = REGISTRY.lock().unwrap();
+    update(&mut series, Metric::RepoPages, &labels, |v| {
+        *v = task.steps.len() as f64
+    });
+    update(&mut series, Metric::RepoItems, &labels, |v| {
+        *v = items as f64
+    });
+    update(&mut series, Metric::RepoCompleted, &labels, |v| {
+        *v = if completed { 1.0 } else { 0.0 }
+    });
+}
+
+/// 按 Prometheus 的文本格式输出
+fn render(series: &Series) -> String {
+    let mut out = String::new();
+    for (metric, values) in series {
+        let name = format!("{PREFIX}_{}", metric.name());
+        out += &format!("# HELP {name} {}\n", metric.help());
+        out += &format!("# TYPE {name} {}\n", metric.kind());
+        for (labels, value) in values {
+            out += &format!("{name}{labels} {value}\n");
+        }
+    }
+    out
+}
+
+fn respond(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 剩下的请求头用不到，读到空行为止。
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split('?').next())
+        .unwrap_or("/");
+    let (status, content_type, body) = if path == "/metrics" {
+        (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(&REGISTRY.lock().unwrap()),
+        )
+    } else {
+        (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "not found\n".to_string(),
+        )
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// 在 `addr` 上启动 `/metrics`，绑定失败时报错，之后在后台线程里一直运行到进程退出。
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).context(format!("metrics 地址 {addr} 绑定失败"))?;
+
+    log::info!("metrics 已启动：http://{addr}/metrics");
+
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(respond);
+                if let Err(e) = result {
+                    log::warn!("metrics 请求处理失败：{e}");
+                }
+            }
+        })
+        .context("metrics 线程创建失败")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    let mut series = Series::new();
+    update(&mut series, Metric::Requests, &[("status", "200")], |v| {
+        *v += 1.0
+    });
+    update(&mut series, Metric::Requests, &[("status", "200")], |v| {
+        *v += 1.0
+    });
+    update(
+        &mut series,
+        Metric::RepoPages,
+        &[("repo", "a/\"b\""), ("task", "issue")],
+        |v| *v = 3.0,
+    );
+
+    assert_eq!(
+        render(&series),
+        "# HELP graphql_github_requests_total 发出的 GraphQL 请求，按响应码区分，连接失败为 error。\n\
+         # TYPE graphql_github_requests_total counter\n\
+         graphql_github_requests_total{status=\"200\"} 2\n\
+         # HELP graphql_github_repo_pages 每个仓库每类任务已经采集的页数\n\
+         # TYPE graphql_github_repo_pages gauge\n\
+         graphql_github_repo_pages{repo=\"a/\\\"b\\\"\",task=\"issue\"} 3\n"
+    );
+}
@@ -0,0 +1,297 @@
+// 外层分页里每个节点的内层连接（issue 的评论、PR 的 commit）只取了前 50 个，
+// 超出的部分按节点 id 用单独的查询翻完，再拼回外层节点里，落盘的分页里内层连接总是完整的。
+//
+// 补齐查询的节点字段需要和外层查询里的保持一致，拼接时按 JSON 转换成外层的节点类型。
+
+use graphql_client::GraphQLQuery;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::cost::CostTotals;
+use crate::error::Error;
+use crate::graphql_client_ext::{self, GithubClient, Window};
+use crate::query::GITHUB_GRAPHQL_URL;
+
+const NESTED_WINDOW: i64 = 100;
+
+/// 内层连接一页的内容，节点以 JSON 的形式返回，方便转换成外层的节点类型。
+pub struct NestedPage {
+    pub nodes: Vec<Value>,
+    pub has_next_page: bool,
+    pub end_cursor: Option<String>,
+}
+
+/// 按节点 id 补齐一个内层连接的查询。
+pub trait NestedConnection:
+    GraphQLQuery<Variables: Window, ResponseData: DeserializeOwned>
+{
+    fn build_variables(
+        node_id: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+    ) -> Self::Variables;
+
+    /// 取出内层连接的这一页，节点已经不存在或者类型不符时返回 None。
+    fn page(data: Self::ResponseData) -> Result<Option<NestedPage>, Error>;
+}
+
+/// 内层连接补齐之后的状态
+pub struct Completed {
+    pub nodes: Vec<Value>,
+    pub end_cursor: Option<String>,
+    /// 补齐这个连接的所有请求的消耗
+    pub cost: CostTotals,
+}
+
+/// 从 `after` 之后把节点 `node_id` 的内层连接翻到最后一页。
+pub fn fetch_remaining<N: NestedConnection>(
+    node_id: &str,
+    after: Option<String>,
+    client: &GithubClient,
+) -> Result<Completed, Error> {
+    let mut completed = Completed {
+        nodes: Vec::new(),
+        end_cursor: after,
+        cost: CostTotals::default(),
+    };
+
+    loop {
+        let variables = N::build_variables(node_id, completed.end_cursor.clone(), NESTED_WINDOW);
+
+        let posted = graphql_client_ext::post_graphql_blocking::<N, _>(
+            client,
+            GITHUB_GRAPHQL_URL,
+            variables,
+        )?;
+        if let Some(cost) = &posted.cost {
+            completed.cost.add(cost);
+        }
+        let response = posted.response;
+
+        if !response.errors.is_empty() {
+            log::warn!(
+                "{node_id} 的内层连接响应带有错误：{}",
+                response.error_summary()
+            );
+        }
+
+        let data = response
+            .data
+            .ok_or_else(|| Error::Graphql(format!("{node_id} 的响应缺少 data")))?;
+
+        let Some(page) = N::page(data)? else {
+            log::warn!(
+                "{node_id} 已经不存在，内层连接停在 {:?}",
+                completed.end_cursor
+            );
+            return Ok(completed);
+        };
+
+        if !append_page(&mut completed, node_id, page)? {
+            return Ok(completed);
+        }
+    }
+}
+
+/// 把一页接到已经补齐的部分后面，返回是否还有下一页。
+///
+/// 还有下一页却没有 endCursor 时报错，否则会用同一个 cursor 一直请求同一页。
+fn append_page(completed: &mut Completed, node_id: &str, page: NestedPage) -> Result<bool, Error> {
+    completed.nodes.extend(page.nodes);
+    match page.end_cursor {
+        Some(end_cursor) => completed.end_cursor = Some(end_cursor),
+        None if page.has_next_page => {
+            return Err(Error::Graphql(format!(
+                "{node_id} 的内层连接还有下一页但没有返回 endCursor，停在 {:?}",
+                completed.end_cursor
+            )))
+        }
+        None => {}
+    }
+    Ok(page.has_next_page)
+}
+
+/// 把补齐的节点转换成外层节点类型，接到外层内层连接的末尾。
+///
+/// 转换失败说明补齐查询和外层查询的节点字段不一致。
+pub fn stitch<T: DeserializeOwned>(
+    nodes: &mut Option<Vec<Option<T>>>,
+    rest: Vec<Value>,
+) -> Result<(), Error> {
+    let rest = rest
+        .into_iter()
+        .map(serde_json::from_value)
+        .collect::<Result<Vec<Option<T>>, _>>()?;
+
+    nodes.get_or_insert_with(Vec::new).extend(rest);
+
+    Ok(())
+}
+
+/// 内层的节点转换成 JSON，空节点保留为 null。
+fn nodes_to_values<T: serde::Serialize>(
+    nodes: Option<Vec<Option<T>>>,
+) -> serde_json::Result<Vec<Value>> {
+    nodes
+        .unwrap_or_default()
+        .into_iter()
+        .map(serde_json::to_value)
+        .collect()
+}
+
+#[allow(clippy::upper_case_acronyms)]
+type DateTime = String;
+
+/// 补齐查询的变量也带有 `query_window: Option<i64>`
+macro_rules! impl_window {
+    ($variables:ty) => {
+        impl Window for $variables {
+            fn get_window(&self) -> i64 {
+                self.query_window.unwrap_or(NESTED_WINDOW)
+            }
+
+            fn set_window(&mut self, window: i64) {
+                self.query_window = Some(window);
+            }
+        }
+    };
+}
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.docs.graphql",
+    query_path = "get_issue_comments.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetIssueComments;
+
+impl_window!(get_issue_comments::Variables);
+
+impl NestedConnection for GetIssueComments {
+    fn build_variables(
+        node_id: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+    ) -> Self::Variables {
+        get_issue_comments::Variables {
+            node_id: node_id.into(),
+            query_cursor,
+            query_window: Some(query_window),
+        }
+    }
+
+    fn page(data: Self::ResponseData) -> Result<Option<NestedPage>, Error> {
+        use get_issue_comments::GetIssueCommentsNode;
+        let Some(GetIssueCommentsNode::Issue(issue)) = data.node else {
+            return Ok(None);
+        };
+
+        let comments = issue.comments;
+        Ok(Some(NestedPage {
+            nodes: nodes_to_values(comments.nodes)?,
+            has_next_page: comments.page_info.has_next_page,
+            end_cursor: comments.page_info.end_cursor,
+        }))
+    }
+}
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.docs.graphql",
+    query_path = "get_pull_request_commits.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetPullRequestCommits;
+
+impl_window!(get_pull_request_commits::Variables);
+
+impl NestedConnection for GetPullRequestCommits {
+    fn build_variables(
+        node_id: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+    ) -> Self::Variables {
+        get_pull_request_commits::Variables {
+            node_id: node_id.into(),
+            query_cursor,
+            query_window: Some(query_window),
+        }
+    }
+
+    fn page(data: Self::ResponseData) -> Result<Option<NestedPage>, Error> {
+        use get_pull_request_commits::GetPullRequestCommitsNode;
+        let Some(GetPullRequestCommitsNode::PullRequest(pull_request)) = data.node else {
+            return Ok(None);
+        };
+
+        let commits = pull_request.commits;
+        Ok(Some(NestedPage {
+            nodes: nodes_to_values(commits.nodes)?,
+            has_next_page: commits.page_info.has_next_page,
+            end_cursor: commits.page_info.end_cursor,
+        }))
+    }
+}
+
+#[test]
+fn test_stitch_into_outer_nodes() -> anyhow::Result<()> {
+    use crate::query::get_closed_issues::GetClosedIssuesRepositoryIssuesNodesComments as Comments;
+    use anyhow::Context;
+
+    let mut comments: Comments = serde_json::from_str(
+        r#"{"pageInfo":{"endCursor":"Y3Vyc29yOjUw","hasNextPage":true},
+            "nodes":[{"author":{"__typename":"User","login":"a"},"bodyText":"first"}]}"#,
+    )?;
+
+    let data: get_issue_comments::ResponseData = serde_json::from_str(
+        r#"{"node":{"__typename":"Issue","comments":{
+            "pageInfo":{"endCursor":"Y3Vyc29yOjUy","hasNextPage":false},
+            "nodes":[{"author":null,"bodyText":"second"},null]}}}"#,
+    )?;
+    let page = GetIssueComments::page(data)?.context("应该是 Issue 节点")?;
+    assert!(!page.has_next_page);
+
+    stitch(&mut comments.nodes, page.nodes)?;
+
+    let nodes = comments.nodes.unwrap_or_default();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(
+        nodes[1].as_ref().map(|c| c.body_text.as_str()),
+        Some("second")
+    );
+    assert!(nodes[2].is_none());
+
+    // 节点已经被删除
+    let gone: get_issue_comments::ResponseData = serde_json::from_str(r#"{"node":null}"#)?;
+    assert!(GetIssueComments::page(gone)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_next_page_without_cursor_is_error() {
+    let mut completed = Completed {
+        nodes: Vec::new(),
+        end_cursor: Some("c50".to_string()),
+        cost: CostTotals::default(),
+    };
+    let page = |has_next_page, end_cursor: Option<&str>| NestedPage {
+        nodes: vec![Value::Null],
+        has_next_page,
+        end_cursor: end_cursor.map(str::to_string),
+    };
+
+    assert!(append_page(&mut completed, "I_1", page(true, Some("c150"))).unwrap());
+    assert_eq!(completed.end_cursor.as_deref(), Some("c150"));
+
+    // 不能拿着同一个 cursor 再请求一次
+    assert!(matches!(
+        append_page(&mut completed, "I_1", page(true, None)),
+        Err(Error::Graphql(_))
+    ));
+
+    // 最后一页没有 endCursor 时保留之前的
+    assert!(!append_page(&mut completed, "I_1", page(false, None)).unwrap());
+    assert_eq!(completed.end_cursor.as_deref(), Some("c150"));
+    assert_eq!(completed.nodes.len(), 3);
+}
@@ -0,0 +1,482 @@
+// 采集状态的导出。
+//
+// crawl 和 retry-failed 每写完一页就把当前进度写到 `<root>/status.json`：每个 worker 正在采集的
+// 仓库、任务和页数，仓库的完成情况，最近一小时的请求次数和查询分数，每个 token 的额度，
+// 正在进行的退避等待和预计完成时间。`status` 命令读取这个文件打印出来，外部监控也可以直接读。
+//
+// 退避等待期间不会有新页写入，另外有一个线程定时刷新，等待的原因和剩余时间同样能看到。
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error;
+use crate::graphql_client_ext::GithubClient;
+use crate::metrics::{self, Metric};
+use crate::util::{self, RateLimit, TaskType};
+
+const STATUS_FILE_NAME: &str = "status.json";
+
+/// 没有新页时刷新状态文件的间隔
+const HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// 退避原因最多保留的字符数，失败响应可能是一整页 html。
+const MAX_REASON_CHARS: usize = 200;
+
+fn current_thread_name() -> String {
+    std::thread::current().name().unwrap_or("main").to_string()
+}
+
+/// 最近一小时的请求次数和查询分数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HourlyUsage {
+    pub requests: u64,
+    pub points: i64,
+}
+
+/// 一次正在进行的退避等待
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backoff {
+    pub reason: String,
+    pub until: DateTime<Utc>,
+}
+
+/// 请求链路上的活动记录，由 `GithubClient` 在发送请求、重试等待时更新。
+pub struct Activity {
+    /// 请求次数和消耗的记录，只保留最近一小时。
+    requests: Mutex<VecDeque<(DateTime<Utc>, HourlyUsage)>>,
+    /// 按线程名记录正在进行的退避等待
+    backoffs: Mutex<BTreeMap<String, Backoff>>,
+}
+
+/// 退避等待结束（包括被退出信号打断）时清掉对应的记录
+pub struct BackoffGuard<'a> {
+    activity: &'a Activity,
+    thread: String,
+}
+
+impl Drop for BackoffGuard<'_> {
+    fn drop(&mut self) {
+        self.activity.backoffs.lock().unwrap().remove(&self.thread);
+    }
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(VecDeque::new()),
+            backoffs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(&self, now: DateTime<Utc>, usage: HourlyUsage) {
+        let mut requests = self.requests.lock().unwrap();
+        Self::prune(&mut requests, now);
+        requests.push_back((now, usage));
+    }
+
+    fn prune(requests: &mut VecDeque<(DateTime<Utc>, HourlyUsage)>, now: DateTime<Utc>) {
+        let cutoff = now - chrono::Duration::hours(1);
+        while requests.front().is_some_and(|(at, _)| *at <= cutoff) {
+            requests.pop_front();
+        }
+    }
+
+    /// 记录一次发出的请求，失败的请求同样计数。
+    pub fn record_request(&self, now: DateTime<Utc>) {
+        self.record(
+            now,
+            HourlyUsage {
+                requests: 1,
+                points: 0,
+            },
+        );
+    }
+
+    /// 记录响应里的查询消耗
+    pub fn record_points(&self, now: DateTime<Utc>, points: i64) {
+        self.record(
+            now,
+            HourlyUsage {
+                requests: 0,
+                points,
+            },
+        );
+    }
+
+    pub fn last_hour(&self, now: DateTime<Utc>) -> HourlyUsage {
+        let mut requests = self.requests.lock().unwrap();
+        Self::prune(&mut requests, now);
+        requests
+            .iter()
+            .fold(HourlyUsage::default(), |total, (_, usage)| HourlyUsage {
+                requests: total.requests + usage.requests,
+                points: total.points + usage.points,
+            })
+    }
+
+    /// 当前线程开始一次退避等待，返回的 guard 释放时结束。
+    pub fn backing_off(&self, reason: impl ToString, wait: Duration) -> BackoffGuard<'_> {
+        let thread = current_thread_name();
+        let backoff = Backoff {
+            reason: reason.to_string().chars().take(MAX_REASON_CHARS).collect(),
+            until: Utc::now()
+                + chrono::Duration::from_std(wait).unwrap_or(chrono::Duration::zero()),
+        };
+        self.backoffs
+            .lock()
+            .unwrap()
+            .insert(thread.clone(), backoff);
+        BackoffGuard {
+            activity: self,
+            thread,
+        }
+    }
+
+    fn backoffs(&self) -> BTreeMap<String, Backoff> {
+        self.backoffs.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    Running,
+    /// 所有仓库都处理过了，其中可能有失败的。
+    Finished,
+    /// 收到退出信号停下
+    Interrupted,
+    /// 遇到致命错误中止
+    Aborted,
+}
+
+impl std::fmt::Display for RunState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunState::Running => write!(f, "running"),
+            RunState::Finished => write!(f, "finished"),
+            RunState::Interrupted => write!(f, "interrupted"),
+            RunState::Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+/// 一个 worker 当前的位置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub repo: Option<String>,
+    pub task: Option<TaskType>,
+    /// 最近写完的一页，这个任务还没有写过页时为 None。
+    pub step: Option<i32>,
+    pub backoff: Option<Backoff>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RepoCounts {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    /// 还没有处理完的，包括正在采集的。
+    pub pending: usize,
+}
+
+/// 一个 token 的额度，文件里只记录编号，不写 token 本身。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenStatus {
+    pub token: usize,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// `status.json` 的内容
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrawlStatus {
+    pub command: String,
+    pub pid: u32,
+    pub state: RunState,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub step_limit: i32,
+    pub workers: Vec<WorkerStatus>,
+    pub repos: RepoCounts,
+    pub last_hour: HourlyUsage,
+    pub rate_limits: Vec<TokenStatus>,
+    /// 按已经处理完的仓库的平均耗时估算，还没有仓库处理完时为 None。
+    pub eta: Option<DateTime<Utc>>,
+}
+
+impl CrawlStatus {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(STATUS_FILE_NAME)
+    }
+
+    /// 读取输出目录里的状态文件，没有运行过 crawl 的目录返回 None。
+    pub fn load(root: &Path) -> Result<Option<Self>> {
+        let path = Self::path(root);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path).context(format!("{path:?} 读取失败"))?;
+        let status = serde_json::from_str(&text).context(format!("{path:?} 解析失败"))?;
+        Ok(Some(status))
+    }
+}
+
+/// 剩下的仓库按已经处理完的仓库的平均耗时估算完成时间
+fn estimate_finish(
+    repos: &RepoCounts,
+    started_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let finished = (repos.done + repos.failed) as i32;
+    if finished == 0 || repos.pending == 0 {
+        return None;
+    }
+    let per_repo = (now - started_at) / finished;
+    Some(now + per_repo * repos.pending as i32)
+}
+
+struct ProgressState {
+    state: RunState,
+    /// 这一批仓库开始的时间，retry-failed 每一轮重新计算。
+    batch_started_at: DateTime<Utc>,
+    repos: RepoCounts,
+    /// 按线程名记录每个 worker 的位置
+    positions: BTreeMap<String, (String, TaskType, Option<i32>)>,
+}
+
+/// 一次 crawl / retry-failed 运行的进度，所有 worker 共享。
+pub struct Progress {
+    path: PathBuf,
+    command: String,
+    step_limit: i32,
+    started_at: DateTime<Utc>,
+    state: Mutex<ProgressState>,
+}
+
+impl Progress {
+    pub fn new(root: &Path, command: &str, step_limit: i32) -> Self {
+        let now = Utc::now();
+        Self {
+            path: CrawlStatus::path(root),
+            command: command.to_string(),
+            step_limit,
+            started_at: now,
+            state: Mutex::new(ProgressState {
+                state: RunState::Running,
+                batch_started_at: now,
+                repos: RepoCounts::default(),
+                positions: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// 开始一批仓库，之前的计数清零。
+    pub fn begin_batch(&self, client: &GithubClient, total: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.batch_started_at = Utc::now();
+            state.repos = RepoCounts {
+                total,
+                pending: total,
+                ..Default::default()
+            };
+            state.positions.clear();
+        }
+        self.update(client);
+    }
+
+    /// 当前线程开始采集一个任务（`step` 为 None），或者写完了这个任务的第 `step` 页。
+    pub fn at(
+        &self,
+        client: &GithubClient,
+        repo_owner: &str,
+        repo_name: &str,
+        task: TaskType,
+        step: Option<i32>,
+    ) {
+        self.state.lock().unwrap().positions.insert(
+            current_thread_name(),
+            (format!("{repo_owner}/{repo_name}"), task, step),
+        );
+        self.update(client);
+    }
+
+    /// 当前线程处理完了一个仓库
+    pub fn repo_finished(&self, client: &GithubClient, ok: bool) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.positions.remove(&current_thread_name());
+            if ok {
+                state.repos.done += 1;
+            } else {
+                state.repos.failed += 1;
+            }
+            state.repos.pending = state.repos.pending.saturating_sub(1);
+        }
+        self.update(client);
+    }
+
+    fn snapshot(&self, state: &ProgressState, client: &GithubClient) -> CrawlStatus {
+        let now = Utc::now();
+
+        let mut workers: BTreeMap<String, WorkerStatus> = state
+            .positions
+            .iter()
+            .map(|(name, (repo, task, step))| {
+                let worker = WorkerStatus {
+                    name: name.clone(),
+                    repo: Some(repo.clone()),
+                    task: Some(*task),
+                    step: *step,
+                    backoff: None,
+                };
+                (name.clone(), worker)
+            })
+            .collect();
+        for (name, backoff) in client.activity.backoffs() {
+            workers
+                .entry(name.clone())
+                .or_insert(WorkerStatus {
+                    name,
+                    repo: None,
+                    task: None,
+                    step: None,
+                    backoff: None,
+                })
+                .backoff = Some(backoff);
+        }
+
+        let rate_limits = client
+            .tokens
+            .snapshot()
+            .into_iter()
+            .enumerate()
+            .map(|(token, (_, rate_limit))| TokenStatus { token, rate_limit })
+            .collect();
+
+        CrawlStatus {
+            command: self.command.clone(),
+            pid: std::process::id(),
+            state: state.state,
+            started_at: self.started_at,
+            updated_at: now,
+            step_limit: self.step_limit,
+            workers: workers.into_values().collect(),
+            repos: state.repos,
+            last_hour: client.activity.last_hour(now),
+            rate_limits,
+            eta: estimate_finish(&state.repos, state.batch_started_at, now),
+        }
+    }
+
+    /// 把当前进度写到状态文件，写不了只记日志，不影响采集。
+    pub fn update(&self, client: &GithubClient) {
+        // 持有锁直到写完，多个 worker 不会同时写同一个临时文件。
+        let state = self.state.lock().unwrap();
+        let status = self.snapshot(&state, client);
+
+        let repos = &status.repos;
+        for (label, count) in [
+            ("done", repos.done),
+            ("failed", repos.failed),
+            ("pending", repos.pending),
+        ] {
+            metrics::set(Metric::Repos, &[("state", label)], count as f64);
+        }
+
+        let written = serde_json::to_vec_pretty(&status)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| util::write_atomic(&self.path, &bytes));
+        if let Err(e) = written {
+            log::warn!("{} 写入失败：{e}", self.path.display());
+        }
+    }
+
+    /// 在 `stop` 的发送端被 drop 之前定时刷新状态文件
+    pub fn heartbeat(&self, client: &GithubClient, stop: Receiver<()>) {
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(HEARTBEAT) {
+            self.update(client);
+        }
+    }
+
+    /// 运行结束时按结果记下最终状态
+    pub fn finish<T>(&self, client: &GithubClient, result: &Result<T>) {
+        let state = match result {
+            Ok(_) => RunState::Finished,
+            Err(e) if error::is_interrupted(e) => RunState::Interrupted,
+            Err(_) => RunState::Aborted,
+        };
+        {
+            let mut progress = self.state.lock().unwrap();
+            progress.state = state;
+            progress.positions.clear();
+        }
+        self.update(client);
+    }
+}
+
+#[test]
+fn test_last_hour_usage() {
+    let activity = Activity::new();
+    let now = Utc::now();
+
+    let earlier = now - chrono::Duration::minutes(90);
+    activity.record_request(earlier);
+    activity.record_points(earlier, 7);
+    let recent = now - chrono::Duration::minutes(30);
+    activity.record_request(recent);
+    activity.record_points(recent, 3);
+    // 失败的请求没有消耗，但同样计数
+    activity.record_request(now);
+
+    assert_eq!(
+        activity.last_hour(now),
+        HourlyUsage {
+            requests: 2,
+            points: 3
+        }
+    );
+
+    {
+        let _backoff = activity.backing_off("x".repeat(1000), Duration::from_secs(60));
+        let backoffs = activity.backoffs();
+        assert_eq!(backoffs.len(), 1);
+        assert_eq!(
+            backoffs.values().next().unwrap().reason.len(),
+            MAX_REASON_CHARS
+        );
+    }
+    assert!(activity.backoffs().is_empty());
+}
+
+#[test]
+fn test_estimate_finish() {
+    let started_at = Utc::now();
+    let now = started_at + chrono::Duration::minutes(30);
+
+    let mut repos = RepoCounts {
+        total: 10,
+        done: 2,
+        failed: 1,
+        pending: 0,
+    };
+    assert_eq!(estimate_finish(&repos, started_at, now), None);
+
+    // 3 个仓库用了 30 分钟，剩下 6 个还要 60 分钟
+    repos.pending = 6;
+    assert_eq!(
+        estimate_finish(&repos, started_at, now),
+        Some(now + chrono::Duration::minutes(60))
+    );
+
+    repos.done = 0;
+    repos.failed = 0;
+    assert_eq!(estimate_finish(&repos, started_at, now), None);
+}
@@ -1,176 +1,467 @@
-use anyhow::Context; // 在这个文件里不要引入 anyhow 的 Result
 use graphql_client::GraphQLQuery;
-use reqwest::blocking;
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::graphql_client_ext;
+use crate::cost::CostTotals;
+use crate::error::Error;
+use crate::estimate::get_repo_totals::GetRepoTotalsRepository;
+use crate::graphql_client_ext::{self, ErrorAction, GithubClient, Window};
+use crate::nested::{self, GetIssueComments, GetPullRequestCommits};
 
-use crate::util;
+use crate::util::TaskType;
+use crate::window::{self, AdaptiveWindow};
 
-pub enum QueryResponseData {
-    Discussions(get_answered_discussions::ResponseData),
-    PRCommits(get_pr_commits::ResponseData),
-    ClosedIssues(get_closed_issues::ResponseData),
+pub(crate) const GITHUB_GRAPHQL_URL: &str = "https://api.github.com/graphql";
+
+// 实际每页的大小由 `AdaptiveWindow` 决定，这里只是变量里没有填写时的默认值。
+const DEFAULT_WINDOW: i64 = window::MAX_WINDOW;
+
+/// 分页的排序方式
+#[derive(Debug, Clone, Copy)]
+pub enum QueryOrder {
+    /// 全量采集：按创建时间正序，新数据只会增长到后面。
+    CreatedAsc,
+    /// 增量同步：按更新时间倒序，支持过滤的连接只取这个时间之后更新过的。
+    UpdatedSince(chrono::DateTime<chrono::Utc>),
 }
 
-pub struct QueryResult {
-    pub is_empty_page: bool,
+impl QueryOrder {
+    fn since(&self) -> Option<DateTime> {
+        match self {
+            QueryOrder::CreatedAsc => None,
+            QueryOrder::UpdatedSince(since) => Some(since.to_rfc3339()),
+        }
+    }
+}
+
+/// 分页连接在一页响应中的状态
+pub struct PageInfo {
+    pub item_count: usize,
     pub has_next_page: bool,
-    pub rate_limit: util::RateLimit,
-    pub query_cursor: Option<String>,
-    pub response_data: QueryResponseData,
+    pub end_cursor: Option<String>,
 }
 
-// 暂时不知道为什么，但是 https://github.com/graphql-rust/graphql-client/blob/main/examples/github/examples/github.rs 案例中这样写。
-#[allow(clippy::upper_case_acronyms)]
-type DateTime = String;
+/// 一类按 cursor 分页的采集任务，直接实现在对应的 `GraphQLQuery` 结构体上。
+///
+/// 新增一类任务只需要一个 `.graphql` 查询文件和这个 trait 的实现，
+/// 再在 `task_registry!` 里加一行。
+pub trait PaginatedTask:
+    GraphQLQuery<Variables: Window, ResponseData: Serialize + DeserializeOwned>
+{
+    const TASK_TYPE: TaskType;
+
+    /// 分页文件中 `repository` 下面对应的连接字段名
+    const CONNECTION: &'static str;
+
+    /// 从 `crawl --dry-run` 的 totalCount 查询里取出这类任务的节点总数，
+    /// 需要在 `get_repo_totals.graphql` 里加上对应的连接。
+    fn total_count(repository: &GetRepoTotalsRepository) -> i64;
+
+    /// 构造一页的查询变量，`query_cursor` 为 None 时查询第一页。
+    fn build_variables(
+        repo_owner: &str,
+        repo_name: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+        order: QueryOrder,
+    ) -> Self::Variables;
+
+    /// 从响应中取出分页连接的信息，仓库不存在时返回 None。
+    fn page_info(data: &Self::ResponseData) -> Option<PageInfo>;
+
+    /// 让查询只计算消耗不执行，见 `estimate`。
+    fn set_dry_run(variables: &mut Self::Variables);
+
+    /// 补齐每个节点里超过第一页的内层连接，返回补齐了多少个节点，额外请求的消耗累加到 `cost`。
+    ///
+    /// 没有内层连接的任务不需要实现。
+    fn complete_nested(
+        _data: &mut Self::ResponseData,
+        _client: &GithubClient,
+        _cost: &mut CostTotals,
+    ) -> Result<usize, Error> {
+        Ok(0)
+    }
+}
 
-#[allow(clippy::upper_case_acronyms)]
-type URI = String;
+/// 所有采集任务的登记表，每行是 `TaskType` 的变体（括号里是命令行、元数据和目录里用的名字）
+/// 和对应的 `PaginatedTask` 实现，按这里的顺序采集。
+///
+/// `TaskType` 和 `for_task_type!` 都由这张表生成，`$callback` 是接收这张表的宏。
+macro_rules! task_registry {
+    ($callback:ident!($($args:tt)*)) => {
+        $crate::query::$callback! {
+            ($($args)*)
+            Discussions("discussion") => GetAnsweredDiscussions,
+            PRCommits("pull_request") => GetPRCommits,
+            ClosedIssues("issue") => GetClosedIssues,
+        }
+    };
+}
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "schemas/schema.docs.graphql",
-    query_path = "get_answered_discussions.graphql",
-    response_derives = "Debug, Serialize, Deserialize, Clone"
-)]
-// 一个 get_repository_discussions 命名的模块会包含进来。
-pub struct GetAnsweredDiscussions;
+pub(crate) use task_registry;
+
+/// 按登记表生成 `TaskType`，在 util.rs 里展开。
+macro_rules! define_task_type {
+    (() $($variant:ident($name:literal) => $task:ident,)*) => {
+        #[derive(
+            Debug,
+            Clone,
+            Copy,
+            PartialEq,
+            Eq,
+            Hash,
+            clap::ValueEnum,
+            serde::Serialize,
+            serde::Deserialize,
+        )]
+        pub enum TaskType {
+            $(
+                #[value(name = $name)]
+                #[serde(rename = $name)]
+                $variant,
+            )*
+        }
+
+        impl TaskType {
+            /// 默认的采集顺序
+            pub const ALL: [TaskType; [$($name),*].len()] = [$(TaskType::$variant),*];
+        }
+
+        impl std::fmt::Display for TaskType {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match *self {
+                    $(TaskType::$variant => write!(f, $name),)*
+                }
+            }
+        }
+    };
+}
 
-impl graphql_client_ext::Window for get_answered_discussions::Variables {
-    fn get_window(&self) -> i64 {
-        self.query_window.unwrap_or(100)
-    }
+pub(crate) use define_task_type;
+
+/// 按登记表生成 `for_task_type!` 的 match
+macro_rules! dispatch_task_type {
+    (($task_type:expr, $alias:ident, $body:expr) $($variant:ident($name:literal) => $task:ident,)*) => {
+        match $task_type {
+            $(
+                $crate::util::TaskType::$variant => {
+                    type $alias = $crate::query::$task;
+                    $body
+                }
+            )*
+        }
+    };
+}
+
+pub(crate) use dispatch_task_type;
+
+/// 把运行时的 `TaskType` 映射为对应的 `PaginatedTask` 实现，`$body` 里用 `$task` 指代该类型。
+///
+/// 例如 `for_task_type!(task_type, T => crawling::<T>(..))`。
+macro_rules! for_task_type {
+    ($task_type:expr, $task:ident => $body:expr) => {
+        $crate::query::task_registry!(dispatch_task_type!($task_type, $task, $body))
+    };
+}
+
+pub(crate) use for_task_type;
+
+pub struct QueryResult<T: PaginatedTask> {
+    pub is_empty_page: bool,
+    pub item_count: usize,
+    pub window: i64,
+    pub has_next_page: bool,
+    pub query_cursor: Option<String>,
+    pub response_data: T::ResponseData,
+    /// 这一页的消耗，包括补齐内层连接的请求。
+    pub cost: CostTotals,
+}
 
-    fn set_window(&mut self, window: i64) {
-        self.query_window = Some(window);
+/// 分页文件是否是完整的一页：能按当前的 `ResponseData` 解析。
+///
+/// 旧版本查询写下的分页少了一些字段（比如节点的 `id`），这种只要求有 `repository.<连接>.nodes`。
+pub fn is_valid_page<T: PaginatedTask>(bytes: &[u8]) -> bool {
+    if serde_json::from_slice::<T::ResponseData>(bytes).is_ok() {
+        return true;
     }
+
+    serde_json::from_slice::<serde_json::Value>(bytes)
+        .ok()
+        .and_then(|data| {
+            data.pointer(&format!("/repository/{}/nodes", T::CONNECTION))
+                .map(serde_json::Value::is_array)
+        })
+        .unwrap_or(false)
 }
 
-pub fn single_discussion_query(
+/// 请求一页数据，窗口大小由 `window` 决定，请求成功后按实际情况调整。
+/// 重试全部失败时 `window` 记下缩小过的大小，调用方需要把它保存到元数据里。
+pub fn single_query<T: PaginatedTask>(
     repo_owner: &str,
     repo_name: &str,
     query_cursor: &Option<String>,
-    client: &blocking::Client,
-) -> anyhow::Result<QueryResult> {
-    // discussion 的查询变量
-    let variables = get_answered_discussions::Variables {
-        repo_owner: repo_owner.into(),
-        repo_name: repo_name.into(),
-        // 此处输入 None 可以获得第一页的内容，随后不断接收 cursor 来访问下一页。
-        query_cursor: query_cursor.clone(),
-        // 虽然 last 或者 first 只能填写 1-100，但是一次请求的 node 上限是 500,000。
-        // 所以设计上一次只请求一个请求 100 个其实有点小。
-        query_window: Some(100),
-    };
-
-    let mut rate_limit = util::RateLimit::default();
-
-    // discussion 的特化查询
-    let response = graphql_client_ext::post_graphql_blocking::<GetAnsweredDiscussions, _>(
+    order: QueryOrder,
+    window: &mut AdaptiveWindow,
+    client: &GithubClient,
+) -> Result<QueryResult<T>, Error> {
+    // 此处输入 None 可以获得第一页的内容，随后不断接收 cursor 来访问下一页。
+    let variables = T::build_variables(
+        repo_owner,
+        repo_name,
+        query_cursor.clone(),
+        window.size,
+        order,
+    );
+
+    let mut last_window = window.size;
+    let posted = graphql_client_ext::post_graphql_with_window::<T, _>(
         client,
-        "https://api.github.com/graphql",
+        GITHUB_GRAPHQL_URL,
         variables,
-        |h| {
-            rate_limit = h.try_into().unwrap_or_default();
-            Ok(())
-        },
-    )
-    .expect("failed to execute query");
+        &mut last_window,
+    );
+    let graphql_client_ext::Posted {
+        response,
+        window: used_window,
+        elapsed,
+        cost: query_cost,
+    } = posted.inspect_err(|_| window.gave_up(last_window))?;
+
+    let mut cost = CostTotals::default();
+    if let Some(query_cost) = &query_cost {
+        cost.add(query_cost);
+    }
+
+    window.observe(used_window, elapsed);
+    log::debug!(
+        "[{}] [{repo_owner}] [{repo_name}] 窗口 {used_window} 耗时 {elapsed:?}，下一页窗口 {}",
+        T::TASK_TYPE,
+        window.size
+    );
+
+    // repository 为 null 时 github 会在 errors 里说明原因，不能当成空页处理。
+    let action = response.action();
+    let errors = response.error_summary();
+    let mut response_data = match response.data {
+        Some(data) if T::page_info(&data).is_some() => {
+            if action.is_some() {
+                log::warn!(
+                    "[{}] [{repo_owner}] [{repo_name}] 响应带有部分错误：{errors}",
+                    T::TASK_TYPE
+                );
+            }
+            data
+        }
+        _ => {
+            return Err(match action {
+                None => Error::RepoUnavailable("repository 为 null".to_string()),
+                Some(ErrorAction::SkipRepo) => Error::RepoUnavailable(errors),
+                Some(_) => Error::Graphql(errors),
+            })
+        }
+    };
 
-    let response_data = response.data.context("missing response data")?;
+    let completed = T::complete_nested(&mut response_data, client, &mut cost)?;
+    if completed > 0 {
+        log::info!(
+            "[{}] [{repo_owner}] [{repo_name}] 补齐了 {completed} 个节点的内层连接",
+            T::TASK_TYPE
+        );
+    }
 
-    let repository = response_data.repository.as_ref();
+    let page_info = T::page_info(&response_data);
 
-    // 这里有实质上的
-    let is_empty_page = repository
-        .and_then(|repo| repo.discussions.nodes.as_ref())
-        .map_or(true, |nodes| nodes.is_empty());
+    let item_count = page_info.as_ref().map_or(0, |p| p.item_count);
 
-    let has_next_page = repository.map_or(false, |repo| repo.discussions.page_info.has_next_page);
+    let is_empty_page = item_count == 0;
+
+    let has_next_page = page_info.as_ref().is_some_and(|p| p.has_next_page);
 
     let query_cursor = if has_next_page {
-        repository.and_then(|repo| repo.discussions.page_info.end_cursor.clone())
+        page_info.and_then(|p| p.end_cursor)
     } else {
         None
     };
 
     Ok(QueryResult {
         is_empty_page,
+        item_count,
+        window: used_window,
         has_next_page,
         query_cursor,
-        rate_limit,
-        response_data: QueryResponseData::Discussions(response_data),
+        response_data,
+        cost,
     })
 }
 
+// 暂时不知道为什么，但是 https://github.com/graphql-rust/graphql-client/blob/main/examples/github/examples/github.rs 案例中这样写。
+#[allow(clippy::upper_case_acronyms)]
+type DateTime = String;
+
+#[allow(clippy::upper_case_acronyms)]
+type URI = String;
+
+/// 所有分页查询的变量都带有 `query_window: Option<i64>`
+macro_rules! impl_window {
+    ($variables:ty) => {
+        impl Window for $variables {
+            fn get_window(&self) -> i64 {
+                self.query_window.unwrap_or(DEFAULT_WINDOW)
+            }
+
+            fn set_window(&mut self, window: i64) {
+                self.query_window = Some(window);
+            }
+        }
+    };
+}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "schemas/schema.docs.graphql",
-    query_path = "get_pr_commits.graphql",
+    query_path = "get_answered_discussions.graphql",
     response_derives = "Debug, Serialize, Deserialize, Clone"
 )]
-pub struct GetPRCommits;
+// 一个 get_repository_discussions 命名的模块会包含进来。
+pub struct GetAnsweredDiscussions;
+
+impl_window!(get_answered_discussions::Variables);
 
-impl graphql_client_ext::Window for get_pr_commits::Variables {
-    fn get_window(&self) -> i64 {
-        self.query_window.unwrap_or(100)
+impl PaginatedTask for GetAnsweredDiscussions {
+    const TASK_TYPE: TaskType = TaskType::Discussions;
+    const CONNECTION: &'static str = "discussions";
+
+    fn total_count(repository: &GetRepoTotalsRepository) -> i64 {
+        repository.discussions.total_count
     }
 
-    fn set_window(&mut self, window: i64) {
-        self.query_window = Some(window);
+    fn build_variables(
+        repo_owner: &str,
+        repo_name: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+        order: QueryOrder,
+    ) -> Self::Variables {
+        use get_answered_discussions::{DiscussionOrderField, OrderDirection};
+        let (order_field, order_direction) = match order {
+            QueryOrder::CreatedAsc => (DiscussionOrderField::CREATED_AT, OrderDirection::ASC),
+            QueryOrder::UpdatedSince(_) => (DiscussionOrderField::UPDATED_AT, OrderDirection::DESC),
+        };
+
+        get_answered_discussions::Variables {
+            repo_owner: repo_owner.into(),
+            repo_name: repo_name.into(),
+            query_cursor,
+            query_window: Some(query_window),
+            order_field,
+            order_direction,
+            dry_run: None,
+        }
     }
-}
 
-pub fn single_pr_commits_query(
-    repo_owner: &str,
-    repo_name: &str,
-    query_cursor: &Option<String>,
-    client: &blocking::Client,
-) -> anyhow::Result<QueryResult> {
-    let variables = get_pr_commits::Variables {
-        repo_owner: repo_owner.into(),
-        repo_name: repo_name.into(),
-        query_cursor: query_cursor.clone(),
-        query_window: Some(100),
-    };
+    fn page_info(data: &Self::ResponseData) -> Option<PageInfo> {
+        let connection = &data.repository.as_ref()?.discussions;
+        Some(PageInfo {
+            item_count: connection.nodes.as_ref().map_or(0, Vec::len),
+            has_next_page: connection.page_info.has_next_page,
+            end_cursor: connection.page_info.end_cursor.clone(),
+        })
+    }
 
-    let mut rate_limit = util::RateLimit::default();
+    fn set_dry_run(variables: &mut Self::Variables) {
+        variables.dry_run = Some(true);
+    }
+}
 
-    // discussion 的特化查询
-    let response = graphql_client_ext::post_graphql_blocking::<GetPRCommits, _>(
-        client,
-        "https://api.github.com/graphql",
-        variables,
-        |h| {
-            rate_limit = h.try_into()?;
-            Ok(())
-        },
-    )
-    .expect("failed to execute query");
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.docs.graphql",
+    query_path = "get_pr_commits.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetPRCommits;
 
-    let response_data = response.data.context("missing response data")?;
+impl_window!(get_pr_commits::Variables);
 
-    let repository = response_data.repository.as_ref();
+impl PaginatedTask for GetPRCommits {
+    const TASK_TYPE: TaskType = TaskType::PRCommits;
+    const CONNECTION: &'static str = "pullRequests";
 
-    let is_empty_page = repository
-        .and_then(|repo| repo.pull_requests.nodes.as_ref())
-        .map_or(true, |nodes| nodes.is_empty());
+    fn total_count(repository: &GetRepoTotalsRepository) -> i64 {
+        repository.pull_requests.total_count
+    }
 
-    let has_next_page = repository.map_or(false, |repo| repo.pull_requests.page_info.has_next_page);
+    fn build_variables(
+        repo_owner: &str,
+        repo_name: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+        order: QueryOrder,
+    ) -> Self::Variables {
+        use get_pr_commits::{IssueOrderField, OrderDirection};
+        let (order_field, order_direction) = match order {
+            QueryOrder::CreatedAsc => (IssueOrderField::CREATED_AT, OrderDirection::ASC),
+            QueryOrder::UpdatedSince(_) => (IssueOrderField::UPDATED_AT, OrderDirection::DESC),
+        };
+
+        get_pr_commits::Variables {
+            repo_owner: repo_owner.into(),
+            repo_name: repo_name.into(),
+            query_cursor,
+            query_window: Some(query_window),
+            order_field,
+            order_direction,
+            dry_run: None,
+        }
+    }
 
-    let query_cursor = if has_next_page {
-        repository.and_then(|repo| repo.pull_requests.page_info.end_cursor.clone())
-    } else {
-        None
-    };
+    fn page_info(data: &Self::ResponseData) -> Option<PageInfo> {
+        let connection = &data.repository.as_ref()?.pull_requests;
+        Some(PageInfo {
+            item_count: connection.nodes.as_ref().map_or(0, Vec::len),
+            has_next_page: connection.page_info.has_next_page,
+            end_cursor: connection.page_info.end_cursor.clone(),
+        })
+    }
 
-    Ok(QueryResult {
-        is_empty_page,
-        has_next_page,
-        query_cursor,
-        rate_limit,
-        response_data: QueryResponseData::PRCommits(response_data),
-    })
+    fn set_dry_run(variables: &mut Self::Variables) {
+        variables.dry_run = Some(true);
+    }
+
+    fn complete_nested(
+        data: &mut Self::ResponseData,
+        client: &GithubClient,
+        cost: &mut CostTotals,
+    ) -> Result<usize, Error> {
+        let Some(nodes) = data
+            .repository
+            .as_mut()
+            .and_then(|r| r.pull_requests.nodes.as_mut())
+        else {
+            return Ok(0);
+        };
+
+        let mut completed = 0;
+        for pull_request in nodes.iter_mut().flatten() {
+            let commits = &mut pull_request.commits;
+            if !commits.page_info.has_next_page {
+                continue;
+            }
+
+            let rest = nested::fetch_remaining::<GetPullRequestCommits>(
+                &pull_request.id,
+                commits.page_info.end_cursor.clone(),
+                client,
+            )?;
+            nested::stitch(&mut commits.nodes, rest.nodes)?;
+            commits.page_info.end_cursor = rest.end_cursor;
+            commits.page_info.has_next_page = false;
+            *cost += rest.cost;
+            completed += 1;
+        }
+
+        Ok(completed)
+    }
 }
 
 #[derive(GraphQLQuery)]
@@ -181,64 +472,156 @@ pub fn single_pr_commits_query(
 )]
 pub struct GetClosedIssues;
 
-impl graphql_client_ext::Window for get_closed_issues::Variables {
-    fn get_window(&self) -> i64 {
-        self.query_window.unwrap_or(100)
+impl_window!(get_closed_issues::Variables);
+
+impl PaginatedTask for GetClosedIssues {
+    const TASK_TYPE: TaskType = TaskType::ClosedIssues;
+    const CONNECTION: &'static str = "issues";
+
+    fn total_count(repository: &GetRepoTotalsRepository) -> i64 {
+        repository.issues.total_count
     }
 
-    fn set_window(&mut self, window: i64) {
-        self.query_window = Some(window);
+    fn build_variables(
+        repo_owner: &str,
+        repo_name: &str,
+        query_cursor: Option<String>,
+        query_window: i64,
+        order: QueryOrder,
+    ) -> Self::Variables {
+        use get_closed_issues::{IssueOrderField, OrderDirection};
+        let (order_field, order_direction) = match order {
+            QueryOrder::CreatedAsc => (IssueOrderField::CREATED_AT, OrderDirection::ASC),
+            QueryOrder::UpdatedSince(_) => (IssueOrderField::UPDATED_AT, OrderDirection::DESC),
+        };
+
+        get_closed_issues::Variables {
+            repo_owner: repo_owner.into(),
+            repo_name: repo_name.into(),
+            query_cursor,
+            query_window: Some(query_window),
+            order_field,
+            order_direction,
+            dry_run: None,
+            since: order.since(),
+        }
     }
-}
 
-pub fn single_issues_query(
-    repo_owner: &str,
-    repo_name: &str,
-    query_cursor: &Option<String>,
-    client: &blocking::Client,
-) -> anyhow::Result<QueryResult> {
-    let variables = get_closed_issues::Variables {
-        repo_owner: repo_owner.into(),
-        repo_name: repo_name.into(),
-        query_cursor: query_cursor.clone(),
-        query_window: Some(100),
-    };
+    fn page_info(data: &Self::ResponseData) -> Option<PageInfo> {
+        let connection = &data.repository.as_ref()?.issues;
+        Some(PageInfo {
+            item_count: connection.nodes.as_ref().map_or(0, Vec::len),
+            has_next_page: connection.page_info.has_next_page,
+            end_cursor: connection.page_info.end_cursor.clone(),
+        })
+    }
 
-    let mut rate_limit = util::RateLimit::default();
+    fn set_dry_run(variables: &mut Self::Variables) {
+        variables.dry_run = Some(true);
+    }
 
-    // discussion 的特化查询
-    let response = graphql_client_ext::post_graphql_blocking::<GetClosedIssues, _>(
-        client,
-        "https://api.github.com/graphql",
-        variables,
-        |h| {
-            rate_limit = h.try_into()?;
-            Ok(())
-        },
-    )
-    .expect("failed to execute query");
+    fn complete_nested(
+        data: &mut Self::ResponseData,
+        client: &GithubClient,
+        cost: &mut CostTotals,
+    ) -> Result<usize, Error> {
+        let Some(nodes) = data
+            .repository
+            .as_mut()
+            .and_then(|r| r.issues.nodes.as_mut())
+        else {
+            return Ok(0);
+        };
+
+        let mut completed = 0;
+        for issue in nodes.iter_mut().flatten() {
+            let comments = &mut issue.comments;
+            if !comments.page_info.has_next_page {
+                continue;
+            }
+
+            let rest = nested::fetch_remaining::<GetIssueComments>(
+                &issue.id,
+                comments.page_info.end_cursor.clone(),
+                client,
+            )?;
+            nested::stitch(&mut comments.nodes, rest.nodes)?;
+            comments.page_info.end_cursor = rest.end_cursor;
+            comments.page_info.has_next_page = false;
+            *cost += rest.cost;
+            completed += 1;
+        }
+
+        Ok(completed)
+    }
+}
 
-    let response_data = response.data.context("missing response data")?;
+#[test]
+fn test_page_info_from_saved_page() -> anyhow::Result<()> {
+    use anyhow::Context;
 
-    let repository = response_data.repository.as_ref();
+    let data: get_pr_commits::ResponseData = serde_json::from_str(
+        r#"{"repository":{"pullRequests":{
+            "pageInfo":{"endCursor":"Y3Vyc29yOjI=","hasNextPage":true},
+            "nodes":[
+                {"id":"PR_1","number":1,"title":"a","url":"https://github.com/o/r/pull/1","updatedAt":"2024-01-01T00:00:00Z","bodyText":"","commits":{"pageInfo":{"endCursor":null,"hasNextPage":false},"nodes":[]}},
+                {"id":"PR_2","number":2,"title":"b","url":"https://github.com/o/r/pull/2","updatedAt":"2024-01-02T00:00:00Z","bodyText":"","commits":{"pageInfo":{"endCursor":null,"hasNextPage":false},"nodes":[]}}
+            ]}}}"#,
+    )?;
 
-    let is_empty_page = repository
-        .and_then(|repo| repo.issues.nodes.as_ref())
-        .map_or(true, |nodes| nodes.is_empty());
+    let page_info = GetPRCommits::page_info(&data).context("repository 不应为空")?;
+    assert_eq!(page_info.item_count, 2);
+    assert!(page_info.has_next_page);
+    assert_eq!(page_info.end_cursor.as_deref(), Some("Y3Vyc29yOjI="));
 
-    let has_next_page = repository.map_or(false, |repo| repo.issues.page_info.has_next_page);
+    let missing: get_pr_commits::ResponseData = serde_json::from_str(r#"{"repository":null}"#)?;
+    assert!(GetPRCommits::page_info(&missing).is_none());
 
-    let query_cursor = if has_next_page {
-        repository.and_then(|repo| repo.issues.page_info.end_cursor.clone())
-    } else {
-        None
-    };
+    Ok(())
+}
 
-    Ok(QueryResult {
-        is_empty_page,
-        has_next_page,
-        query_cursor,
-        rate_limit,
-        response_data: QueryResponseData::ClosedIssues(response_data),
-    })
+#[test]
+fn test_is_valid_page() {
+    let current = br#"{"repository":{"pullRequests":{
+        "pageInfo":{"endCursor":null,"hasNextPage":false},
+        "nodes":[{"id":"PR_1","number":1,"title":"a","url":"https://github.com/o/r/pull/1","updatedAt":"2024-01-01T00:00:00Z","bodyText":"","commits":{"pageInfo":{"endCursor":null,"hasNextPage":false},"nodes":[]}}]}}}"#;
+    assert!(is_valid_page::<GetPRCommits>(current));
+
+    // 旧版本写下的分页缺少 id 等字段，只要结构对就认
+    let legacy = br#"{"repository":{"pullRequests":{"nodes":[{"number":1}]}}}"#;
+    assert!(is_valid_page::<GetPRCommits>(legacy));
+
+    // 写了一半的文件、其他任务类型的分页都不认
+    assert!(!is_valid_page::<GetPRCommits>(
+        &current[..current.len() / 2]
+    ));
+    assert!(!is_valid_page::<GetPRCommits>(
+        br#"{"repository":{"issues":{"nodes":[]}}}"#
+    ));
+    assert!(!is_valid_page::<GetPRCommits>(b""));
+}
+
+#[test]
+fn test_task_registry() {
+    use clap::ValueEnum;
+
+    assert_eq!(
+        TaskType::ALL,
+        [
+            TaskType::Discussions,
+            TaskType::PRCommits,
+            TaskType::ClosedIssues
+        ]
+    );
+    for task_type in TaskType::ALL {
+        // 命令行、元数据和目录名用的是同一个名字
+        let name = task_type.to_string();
+        assert_eq!(
+            serde_json::to_string(&task_type).unwrap(),
+            format!("\"{name}\"")
+        );
+        assert_eq!(TaskType::from_str(&name, false), Ok(task_type));
+        assert_eq!(for_task_type!(task_type, T => T::TASK_TYPE), task_type);
+    }
+    assert_eq!(TaskType::PRCommits.connection_name(), "pullRequests");
 }
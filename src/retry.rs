@@ -0,0 +1,166 @@
+use rand::Rng;
+use reqwest::StatusCode;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// 收到某个响应码之后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusAction {
+    /// 直接失败，比如 token 无效，重试不会有任何变化。
+    Abort,
+    /// 次要速率限制，按 retry-after 或者至少一分钟等待。
+    SecondaryLimit,
+    /// 跳过整个仓库
+    Skip,
+    /// 按指数退避等待后重试
+    Backoff,
+    /// 返回数据太大，缩小窗口之后按指数退避重试。
+    ShrinkWindow,
+}
+
+/// `post_graphql_blocking` 的重试策略，对应 config 里的 `retry`。
+///
+/// 参考 https://docs.github.com/en/rest/using-the-rest-api/best-practices-for-using-the-rest-api#handle-rate-limit-errors-appropriately
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// 一个请求最多发送几次，包括第一次。
+    pub max_attempts: u32,
+    /// 指数退避的基础等待秒数，第 n 次重试等待 `base_delay_secs << n`。
+    pub base_delay_secs: u64,
+    /// 单次等待的上限
+    pub max_delay_secs: u64,
+    /// 一个请求累计退避等待的上限，超过之后不再重试，把最后一次的错误返回给调用方。
+    ///
+    /// 不包括等待主要速率限制重置，那是额度用完之后的正常等待，最长一小时。
+    pub max_total_wait_secs: u64,
+    /// 在等待时间上加减的随机比例，避免多个 worker 同时醒来。
+    pub jitter: f64,
+    /// 缩小窗口时乘上的比例
+    pub window_shrink: f64,
+    /// 连接失败、超时等网络错误是否按指数退避重试
+    pub retry_transport_errors: bool,
+    /// 按响应码覆盖默认的处理方式，例如 `404: backoff`。
+    pub statuses: BTreeMap<u16, StatusAction>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            // 重试 7 次，累计 5 + 10 + ... + 640 秒，加上次要速率限制的等待大约一小时
+            max_attempts: 8,
+            base_delay_secs: 5,
+            max_delay_secs: 30 * 60,
+            max_total_wait_secs: 60 * 60,
+            jitter: 0.2,
+            window_shrink: 2.0 / 3.0,
+            retry_transport_errors: true,
+            statuses: BTreeMap::new(),
+        }
+    }
+}
+
+/// 次要速率限制没有明确的重试时间时至少等待一分钟
+const SECONDARY_LIMIT_MIN_SECS: u64 = 60;
+
+impl RetryPolicy {
+    /// 非 200 响应码的处理方式，config 里没有覆盖的按默认处理。
+    pub fn action(&self, status: StatusCode) -> StatusAction {
+        if let Some(action) = self.statuses.get(&status.as_u16()) {
+            return *action;
+        }
+
+        match status {
+            StatusCode::UNAUTHORIZED => StatusAction::Abort,
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => StatusAction::SecondaryLimit,
+            StatusCode::NOT_FOUND => StatusAction::Skip,
+            // https://github.com/orgs/community/discussions/24631#discussioncomment-3244785
+            // 但在实际情况中，502 504 的情况一般是数据规模太大导致。
+            StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => StatusAction::ShrinkWindow,
+            status if status.is_server_error() => StatusAction::Backoff,
+            // 其他 4xx 是请求本身有问题
+            status if status.is_client_error() => StatusAction::Abort,
+            _ => StatusAction::Backoff,
+        }
+    }
+
+    /// 第 `retry_step` 次重试（从 0 开始）的指数退避时间，不含随机抖动。
+    pub fn backoff_secs(&self, retry_step: u32) -> u64 {
+        self.base_delay_secs
+            .checked_shl(retry_step)
+            .unwrap_or(u64::MAX)
+            .min(self.max_delay_secs)
+    }
+
+    /// 次要速率限制的等待时间，有 retry-after 时以它为准。
+    pub fn secondary_limit_secs(&self, retry_step: u32, retry_after: Option<u64>) -> u64 {
+        retry_after.unwrap_or_else(|| self.backoff_secs(retry_step).max(SECONDARY_LIMIT_MIN_SECS))
+    }
+
+    pub fn shrink(&self, window: i64) -> i64 {
+        ((window as f64 * self.window_shrink) as i64).max(1)
+    }
+
+    /// 加上随机抖动之后的等待时间
+    pub fn with_jitter(&self, secs: u64) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 || secs == 0 {
+            return Duration::from_secs(secs);
+        }
+
+        let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        Duration::from_secs_f64(secs as f64 * factor)
+    }
+}
+
+#[test]
+fn test_default_status_actions() {
+    let policy = RetryPolicy::default();
+
+    assert_eq!(policy.action(StatusCode::UNAUTHORIZED), StatusAction::Abort);
+    assert_eq!(
+        policy.action(StatusCode::FORBIDDEN),
+        StatusAction::SecondaryLimit
+    );
+    assert_eq!(policy.action(StatusCode::NOT_FOUND), StatusAction::Skip);
+    assert_eq!(
+        policy.action(StatusCode::BAD_GATEWAY),
+        StatusAction::ShrinkWindow
+    );
+    assert_eq!(
+        policy.action(StatusCode::SERVICE_UNAVAILABLE),
+        StatusAction::Backoff
+    );
+    assert_eq!(policy.action(StatusCode::BAD_REQUEST), StatusAction::Abort);
+
+    let policy: RetryPolicy = serde_yaml::from_str("statuses: { 404: backoff }").unwrap();
+    assert_eq!(policy.action(StatusCode::NOT_FOUND), StatusAction::Backoff);
+    assert_eq!(policy.max_attempts, RetryPolicy::default().max_attempts);
+}
+
+#[test]
+fn test_delays() {
+    let policy = RetryPolicy {
+        base_delay_secs: 5,
+        max_delay_secs: 100,
+        jitter: 0.5,
+        ..Default::default()
+    };
+
+    assert_eq!(policy.backoff_secs(0), 5);
+    assert_eq!(policy.backoff_secs(3), 40);
+    assert_eq!(policy.backoff_secs(10), 100);
+    assert_eq!(policy.backoff_secs(80), 100);
+
+    assert_eq!(policy.secondary_limit_secs(0, None), 60);
+    assert_eq!(policy.secondary_limit_secs(0, Some(7)), 7);
+
+    assert_eq!(policy.shrink(100), 66);
+    assert_eq!(policy.shrink(1), 1);
+
+    for _ in 0..20 {
+        let delay = policy.with_jitter(10);
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+    }
+}
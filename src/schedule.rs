@@ -0,0 +1,163 @@
+// 速率限制的重置时间统一在这里计算。
+//
+// `x-ratelimit-reset` 和 `rateLimit.resetAt` 都是 UTC 的 epoch 秒数，和时区无关，
+// 但它们是按 github 服务器的时钟给出的。本机时钟不准的时候，直接拿本机时间去比较
+// 会过早重试（又被限流）或者白白多等，所以每个响应都用 `Date` 标头校准一次时钟偏差。
+
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+/// 等待重置时额外多等的秒数。`Date` 只精确到秒，再加上请求本身的耗时，偏差估计会有一两秒的误差。
+const RESET_MARGIN_SECS: u64 = 2;
+
+/// 等待重置的上限。额度按一小时的窗口重置，再久的等待只可能是标头的值不对，
+/// 按这个上限等完之后重新发请求，拿到新的重置时间。
+const MAX_WAIT_SECS: u64 = 3600 + RESET_MARGIN_SECS;
+
+/// 所有 worker 共享的重置时间调度，记录服务器时钟相对本机时钟的偏差。
+#[derive(Debug, Default)]
+pub struct ResetScheduler {
+    /// 服务器时间减去本机时间，单位秒。
+    skew_secs: AtomicI64,
+}
+
+impl ResetScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 用响应的 `Date` 标头更新时钟偏差，没有或者解析不了时保持原来的值。
+    pub fn observe(&self, headers: &HeaderMap) {
+        let Some(date) = headers
+            .get(reqwest::header::DATE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return;
+        };
+
+        if let Some(skew) = skew_from_date(date, local_now()) {
+            let previous = self.skew_secs.swap(skew, Ordering::Relaxed);
+            if (skew - previous).abs() > 1 {
+                log::info!("本机时钟与服务器相差 {skew}s");
+            }
+        }
+    }
+
+    pub fn skew_secs(&self) -> i64 {
+        self.skew_secs.load(Ordering::Relaxed)
+    }
+
+    /// 按服务器时钟的当前 epoch 秒数，和 `x-ratelimit-reset` 比较时用这个。
+    pub fn server_now(&self) -> i64 {
+        local_now() + self.skew_secs()
+    }
+
+    /// 距离 `reset_epoch` 还要等多久，已经过了重置时间时返回 0，最多等 `MAX_WAIT_SECS`。
+    pub fn until_reset(&self, reset_epoch: i64) -> Duration {
+        let server_now = self.server_now();
+        let secs = wait_secs(reset_epoch, server_now);
+        if secs > MAX_WAIT_SECS {
+            log::warn!(
+                "重置时间 {reset_epoch} 距离现在（{server_now}）超过一个窗口，按 {MAX_WAIT_SECS}s 等待"
+            );
+            return Duration::from_secs(MAX_WAIT_SECS);
+        }
+        Duration::from_secs(secs)
+    }
+}
+
+fn local_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// `Date` 标头（RFC 7231 的 IMF-fixdate，例如 `Sun, 06 Nov 1994 08:49:37 GMT`）相对本机时间的偏差
+fn skew_from_date(date: &str, local_now: i64) -> Option<i64> {
+    let server = chrono::DateTime::parse_from_rfc2822(date).ok()?;
+    Some(server.timestamp() - local_now)
+}
+
+/// 两个 epoch 秒数相减，重置时间已经过去时为 0，还没到时加上余量。
+fn wait_secs(reset_epoch: i64, server_now: i64) -> u64 {
+    // 用 i128 相减，标头里的值再离谱也不会溢出。
+    let diff = reset_epoch as i128 - server_now as i128;
+    if diff <= 0 {
+        // 刚好到点或者已经过去，不需要等待。
+        return 0;
+    }
+    u64::try_from(diff)
+        .unwrap_or(u64::MAX)
+        .saturating_add(RESET_MARGIN_SECS)
+}
+
+#[test]
+fn test_wait_secs_edges() {
+    // 已经过了重置时间，不能下溢成一个巨大的等待时间
+    assert_eq!(wait_secs(1_700_000_000, 1_700_000_100), 0);
+    assert_eq!(wait_secs(1_700_000_000, 1_700_000_000), 0);
+    assert_eq!(
+        wait_secs(1_700_000_060, 1_700_000_000),
+        60 + RESET_MARGIN_SECS
+    );
+    assert_eq!(wait_secs(i64::MIN, i64::MAX), 0);
+    // 缺少标头时 reset 按 0 处理的情况
+    assert_eq!(wait_secs(0, 1_700_000_000), 0);
+
+    // 离谱的重置时间最多等一个窗口
+    let scheduler = ResetScheduler::new();
+    assert_eq!(
+        scheduler.until_reset(i64::MAX),
+        Duration::from_secs(MAX_WAIT_SECS)
+    );
+    assert_eq!(
+        scheduler.until_reset(local_now() + 10 * 3600),
+        Duration::from_secs(MAX_WAIT_SECS)
+    );
+}
+
+#[test]
+fn test_skew_from_date() {
+    let local_now = 1_700_000_000; // 2023-11-14T22:13:20Z
+
+    assert_eq!(
+        skew_from_date("Tue, 14 Nov 2023 22:13:20 GMT", local_now),
+        Some(0)
+    );
+    // 本机慢了 90 秒
+    assert_eq!(
+        skew_from_date("Tue, 14 Nov 2023 22:14:50 GMT", local_now),
+        Some(90)
+    );
+    // 本机快了 5 秒
+    assert_eq!(
+        skew_from_date("Tue, 14 Nov 2023 22:13:15 GMT", local_now),
+        Some(-5)
+    );
+    assert_eq!(skew_from_date("not a date", local_now), None);
+    assert_eq!(skew_from_date("", local_now), None);
+}
+
+#[test]
+fn test_until_reset_uses_server_clock() {
+    let scheduler = ResetScheduler::new();
+    let now = local_now();
+
+    // 本机时钟慢了 100 秒：服务器已经过了重置时间，不应该再等。
+    let mut headers = HeaderMap::new();
+    let server_date = chrono::DateTime::from_timestamp(now + 100, 0).unwrap();
+    headers.insert(
+        reqwest::header::DATE,
+        server_date.to_rfc2822().parse().unwrap(),
+    );
+    scheduler.observe(&headers);
+    assert!((99..=101).contains(&scheduler.skew_secs()));
+    assert_eq!(scheduler.until_reset(now + 50), Duration::ZERO);
+
+    // 解析不了的 Date 不影响已有的偏差
+    headers.insert(reqwest::header::DATE, "garbage".parse().unwrap());
+    scheduler.observe(&headers);
+    assert!((99..=101).contains(&scheduler.skew_secs()));
+
+    let wait = scheduler.until_reset(now + 400).as_secs();
+    assert!((300..=303).contains(&wait), "{wait}");
+}
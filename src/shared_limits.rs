@@ -0,0 +1,178 @@
+// 同一个 token 被多个进程同时使用时（例如按仓库分片起了几个实例），各自只看得到自己的
+// 请求，都会按满额的速度去用，结果一起被限流。
+//
+// 这里把每个 token 最新的 `RateLimit` 放在一个本机共享的 JSON 文件里，用文件锁保护。
+// 每个请求之前读一次、合并进自己的 token 池，拿到响应之后写回去。文件里只记录 token 的
+// 哈希，不保存 token 本身。
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::token_pool::TokenPool;
+use crate::util::RateLimit;
+
+/// 多久没有请求的进程不再算作在共用这个 token
+const STALE_USER_SECS: i64 = 120;
+
+/// 默认的共享文件，放在系统临时目录里，同一台机器上的所有实例都能看到。
+pub fn default_path() -> PathBuf {
+    std::env::temp_dir().join("graphql_github_rate_limits.json")
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SharedFile {
+    /// token 哈希 → 状态
+    #[serde(default)]
+    tokens: BTreeMap<String, SharedEntry>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SharedEntry {
+    rate_limit: Option<RateLimit>,
+    /// 正在使用这个 token 的进程和它们最后一次请求的时间
+    #[serde(default)]
+    users: BTreeMap<String, i64>,
+}
+
+/// 本机所有进程共享的 rate limit 状态
+pub struct SharedRateLimits {
+    path: PathBuf,
+    /// 当前进程的标识
+    me: String,
+}
+
+impl SharedRateLimits {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            me: std::process::id().to_string(),
+        }
+    }
+
+    /// 请求之前调用：把其他进程看到的额度合并进 token 池，同时登记当前进程还在使用这些 token。
+    pub fn sync(&self, pool: &TokenPool, now: i64) -> io::Result<()> {
+        self.with_locked(|shared| {
+            for (index, (token, own)) in pool.snapshot().into_iter().enumerate() {
+                let entry = shared.tokens.entry(token_key(&token)).or_default();
+
+                entry.users.insert(self.me.clone(), now);
+                entry
+                    .users
+                    .retain(|_, last_seen| *last_seen > now - STALE_USER_SECS);
+
+                let merged = merge(entry.rate_limit, own);
+                entry.rate_limit = merged;
+                pool.merge_shared(index, merged, entry.users.len());
+            }
+        })
+    }
+
+    /// 拿到响应之后调用，把这个 token 最新的额度写回去。
+    pub fn publish(&self, token: &str, rate_limit: RateLimit) -> io::Result<()> {
+        self.with_locked(|shared| {
+            let entry = shared.tokens.entry(token_key(token)).or_default();
+            entry.rate_limit = merge(entry.rate_limit, Some(rate_limit));
+        })
+    }
+
+    /// 加上排他锁读出整个文件，修改之后原地写回。锁随着文件关闭释放。
+    fn with_locked(&self, f: impl FnOnce(&mut SharedFile)) -> io::Result<()> {
+        let mut file = open(&self.path)?;
+        file.lock()?;
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        // 文件损坏时当成空的，下一次写入会覆盖掉。
+        let mut shared: SharedFile = serde_json::from_str(&text).unwrap_or_default();
+
+        f(&mut shared);
+
+        file.set_len(0)?;
+        file.rewind()?;
+        serde_json::to_writer(&mut file, &shared)?;
+        file.flush()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// token 的 FNV-1a 哈希，不同版本的编译器算出来都一样。
+fn token_key(token: &str) -> String {
+    let hash = token.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// 两份记录里取更新的一份：重置时间不同的以后一个窗口为准，同一个窗口里取剩余更少的。
+fn merge(a: Option<RateLimit>, b: Option<RateLimit>) -> Option<RateLimit> {
+    match (a, b) {
+        (Some(a), Some(b)) if a.reset != b.reset => Some(if a.reset > b.reset { a } else { b }),
+        (Some(a), Some(b)) => Some(if a.remaining <= b.remaining { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+#[test]
+fn test_merge_prefers_newer_window_then_lower_remaining() {
+    let old = RateLimit::new(5000, 10, 4990, 1_000);
+    let new = RateLimit::new(5000, 4000, 1000, 4_600);
+    let other = RateLimit::new(5000, 3500, 1500, 4_600);
+
+    assert_eq!(merge(Some(old), Some(new)).unwrap().reset, 4_600);
+    assert_eq!(merge(Some(new), Some(old)).unwrap().reset, 4_600);
+    assert_eq!(merge(Some(new), Some(other)).unwrap().remaining, 3500);
+    assert_eq!(merge(None, Some(old)).unwrap().remaining, 10);
+    assert!(merge(None, None).is_none());
+
+    assert_eq!(token_key("github_pat_a"), token_key("github_pat_a"));
+    assert_ne!(token_key("github_pat_a"), token_key("github_pat_b"));
+}
+
+#[test]
+fn test_two_processes_share_usage() {
+    use crate::token_pool::Reserve;
+
+    let path = std::env::temp_dir().join(format!(
+        "graphql_github_shared_test_{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let now = chrono::Utc::now().timestamp();
+
+    let first = SharedRateLimits {
+        path: path.clone(),
+        me: "1".into(),
+    };
+    let second = SharedRateLimits {
+        path: path.clone(),
+        me: "2".into(),
+    };
+    let first_pool = TokenPool::new(vec!["t".into()], Reserve::None);
+    let second_pool = TokenPool::new(vec!["t".into()], Reserve::None);
+
+    first.sync(&first_pool, now).unwrap();
+    first
+        .publish("t", RateLimit::new(5000, 0, 5000, now + 600))
+        .unwrap();
+
+    // 第二个进程还没发过请求，同步之后也知道这个 token 已经用完了。
+    assert!(second_pool.has_available(now));
+    second.sync(&second_pool, now).unwrap();
+    assert_eq!(second_pool.acquire(now), Err(now + 600));
+
+    std::fs::remove_file(&path).unwrap();
+}
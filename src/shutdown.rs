@@ -0,0 +1,98 @@
+// 收到 SIGINT/SIGTERM（Windows 上是 Ctrl-C/关闭窗口）时不立即退出，而是让正在进行的
+// 那一页写完、元数据保存好之后再停下，下次运行从断点继续。
+//
+// 各个循环在每一页之前检查 `requested()`；重试和等待重置用 `sleep()`，收到信号时提前醒来。
+// 再收到一次信号就不再等待，直接退出。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// 主动停止时的退出码，和出错退出（1）、跑完但有失败（3）、panic（101）区分开，
+/// 守护脚本据此判断要不要重启。
+pub const EXIT_CODE: i32 = 130;
+
+/// 检查间隔，也就是收到信号之后 `sleep` 最多还要多久才返回。
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct Shutdown {
+    requested: AtomicBool,
+}
+
+static GLOBAL: Shutdown = Shutdown::new();
+
+impl Shutdown {
+    const fn new() -> Self {
+        Self {
+            requested: AtomicBool::new(false),
+        }
+    }
+
+    fn request(&self) -> bool {
+        self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn sleep(&self, duration: Duration) -> bool {
+        // 太长的等待算不出截止时间，就一直等到收到退出信号。
+        let deadline = Instant::now().checked_add(duration);
+        loop {
+            if self.requested() {
+                return false;
+            }
+            let now = Instant::now();
+            let remaining = match deadline {
+                Some(deadline) if now >= deadline => return true,
+                Some(deadline) => deadline - now,
+                None => POLL_INTERVAL,
+            };
+            std::thread::sleep(remaining.min(POLL_INTERVAL));
+        }
+    }
+}
+
+/// 注册信号处理
+pub fn install() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if GLOBAL.request() {
+            log::warn!("再次收到退出信号，立即退出");
+            std::process::exit(EXIT_CODE);
+        }
+        log::warn!("收到退出信号，当前这一页完成后保存进度并退出，再按一次立即退出");
+    })?;
+    Ok(())
+}
+
+/// 是否已经收到退出信号
+pub fn requested() -> bool {
+    GLOBAL.requested()
+}
+
+/// 可以被退出信号打断的 sleep，完整睡完返回 true，被打断返回 false。
+pub fn sleep(duration: Duration) -> bool {
+    GLOBAL.sleep(duration)
+}
+
+#[test]
+fn test_sleep_wakes_up_on_request() {
+    let shutdown = Shutdown::new();
+    assert!(shutdown.sleep(Duration::from_millis(10)));
+
+    let begin = Instant::now();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!shutdown.request());
+        });
+        assert!(!shutdown.sleep(Duration::from_secs(30)));
+    });
+    assert!(begin.elapsed() < Duration::from_secs(1));
+
+    // 第二次请求能看出来之前已经请求过
+    assert!(shutdown.request());
+
+    // 截止时间溢出时不会 panic
+    assert!(!shutdown.sleep(Duration::MAX));
+}
@@ -0,0 +1,243 @@
+// `supervise`：以子进程运行采集，异常退出时按退避时间重启。
+//
+// 原来的 `demon.ps1` 只能在 Windows 上用，写死了 cargo.exe 的路径，并且不管退出原因
+// 每 5 秒重启一次。这里直接重新启动当前的可执行文件：
+//
+// - 子进程正常结束（退出码 0）、跑完但有仓库失败（`error::FAILURES_EXIT_CODE`）
+//   或者被手动停止（`shutdown::EXIT_CODE`）时不再重启，失败的仓库交给 `retry-failed`；
+// - 其他退出都按崩溃处理，运行时间很短的崩溃连续出现时退避时间翻倍，次数太多就放弃，
+//   比如配置写错、输出目录被其他实例锁住，重启多少次都不会好。
+//
+// 子进程放在单独的进程组里，终端的 Ctrl-C 只发给 supervise；supervise 收到 Ctrl-C 或者
+// SIGTERM 之后给子进程转发一次 SIGTERM，等它保存进度、释放输出目录的锁之后再退出，
+// 不会留下还在运行的子进程。Windows 上 Ctrl-C 本来就会发给同一个控制台里的所有进程。
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crate::cli::SuperviseArgs;
+use crate::{error, shutdown};
+
+/// 子进程的退出方式
+#[derive(Debug, PartialEq, Eq)]
+enum Exit {
+    /// 整批跑完，没有失败
+    Finished,
+    /// 整批跑完，有仓库失败
+    FinishedWithFailures,
+    /// 收到退出信号，保存进度之后停下
+    Stopped,
+    /// 其他退出码和被信号杀掉
+    Crashed,
+}
+
+impl Exit {
+    fn from_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => Exit::Finished,
+            Some(error::FAILURES_EXIT_CODE) => Exit::FinishedWithFailures,
+            Some(shutdown::EXIT_CODE) => Exit::Stopped,
+            _ => Exit::Crashed,
+        }
+    }
+}
+
+/// 根据每次运行的时长决定下一次重启前等多久
+struct RestartPolicy {
+    min_backoff: Duration,
+    max_backoff: Duration,
+    fast_crash: Duration,
+    max_fast_crashes: u32,
+    /// 连续快速崩溃的次数
+    fast_crashes: u32,
+}
+
+impl RestartPolicy {
+    fn new(args: &SuperviseArgs) -> Self {
+        Self {
+            min_backoff: Duration::from_secs(args.min_backoff_secs),
+            max_backoff: Duration::from_secs(args.max_backoff_secs),
+            fast_crash: Duration::from_secs(args.fast_crash_secs),
+            max_fast_crashes: args.max_fast_crashes,
+            fast_crashes: 0,
+        }
+    }
+
+    /// 一次崩溃之后的等待时间，连续快速崩溃达到上限时返回 None。
+    fn after_crash(&mut self, ran_for: Duration) -> Option<Duration> {
+        if ran_for >= self.fast_crash {
+            // 跑了一段时间才退出，说明之前的问题已经过去了，从头计算。
+            self.fast_crashes = 0;
+            return Some(self.min_backoff);
+        }
+
+        self.fast_crashes += 1;
+        if self.fast_crashes >= self.max_fast_crashes {
+            return None;
+        }
+
+        let backoff = self
+            .min_backoff
+            .checked_mul(1 << (self.fast_crashes - 1).min(16))
+            .unwrap_or(self.max_backoff);
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+/// 检查子进程是否退出的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 让子进程保存进度后退出
+#[cfg(unix)]
+fn terminate(child: &Child) -> std::io::Result<()> {
+    // SAFETY: 只是给自己启动、还没有回收的子进程发信号。
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(_child: &Child) -> std::io::Result<()> {
+    // 同一个控制台里的子进程自己会收到 Ctrl-C
+    Ok(())
+}
+
+/// 等子进程退出。`stop()` 变成 true 之后给子进程转发一次退出信号，继续等它保存进度退出。
+fn wait_child(child: &mut Child, stop: impl Fn() -> bool) -> std::io::Result<ExitStatus> {
+    let mut forwarded = false;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if !forwarded && stop() {
+            log::info!("[supervise] 收到退出信号，等子进程保存进度后退出");
+            terminate(child)?;
+            forwarded = true;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+pub fn run(log_config: &Path, args: &SuperviseArgs) -> Result<()> {
+    if args.command.first().is_some_and(|c| c == "supervise") {
+        bail!("子进程不能再是 supervise");
+    }
+
+    let exe = std::env::current_exe().context("找不到当前可执行文件的路径")?;
+    let mut policy = RestartPolicy::new(args);
+
+    for run in 1.. {
+        log::info!(
+            "[supervise] 第 {run} 次启动：{} {}",
+            exe.display(),
+            args.command.join(" ")
+        );
+
+        let begin = Instant::now();
+        let mut command = Command::new(&exe);
+        command
+            .arg("--log-config")
+            .arg(log_config)
+            .args(&args.command);
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn().context("子进程启动失败")?;
+        let status = wait_child(&mut child, shutdown::requested).context("等待子进程失败")?;
+        let ran_for = begin.elapsed();
+
+        log::info!("[supervise] 子进程退出：{status}，运行了 {ran_for:?}");
+
+        match Exit::from_code(status.code()) {
+            Exit::Finished => {
+                log::info!("[supervise] 采集正常结束，不再重启");
+                return Ok(());
+            }
+            Exit::FinishedWithFailures => {
+                log::warn!("[supervise] 采集已经跑完，有仓库失败，不再重启，用 retry-failed 重试");
+                std::process::exit(error::FAILURES_EXIT_CODE);
+            }
+            Exit::Stopped => {
+                log::info!("[supervise] 子进程被手动停止，不再重启");
+                std::process::exit(shutdown::EXIT_CODE);
+            }
+            Exit::Crashed => {}
+        }
+
+        if shutdown::requested() {
+            log::info!("[supervise] 收到退出信号，不再重启");
+            std::process::exit(shutdown::EXIT_CODE);
+        }
+
+        let Some(backoff) = policy.after_crash(ran_for) else {
+            bail!(
+                "子进程连续 {} 次在 {:?} 内崩溃，放弃重启，最后一次：{status}",
+                policy.fast_crashes,
+                policy.fast_crash
+            );
+        };
+
+        log::warn!("[supervise] {backoff:?} 后重启");
+        if !shutdown::sleep(backoff) {
+            log::info!("[supervise] 收到退出信号，不再重启");
+            std::process::exit(shutdown::EXIT_CODE);
+        }
+    }
+
+    unreachable!("重启循环只会从内部返回")
+}
+
+#[test]
+fn test_restart_policy() {
+    let mut policy = RestartPolicy {
+        min_backoff: Duration::from_secs(5),
+        max_backoff: Duration::from_secs(30),
+        fast_crash: Duration::from_secs(60),
+        max_fast_crashes: 5,
+        fast_crashes: 0,
+    };
+    let fast = Duration::from_secs(1);
+    let slow = Duration::from_secs(3600);
+
+    assert_eq!(policy.after_crash(fast), Some(Duration::from_secs(5)));
+    assert_eq!(policy.after_crash(fast), Some(Duration::from_secs(10)));
+    assert_eq!(policy.after_crash(fast), Some(Duration::from_secs(20)));
+    assert_eq!(policy.after_crash(fast), Some(Duration::from_secs(30)));
+    // 第五次连续快速崩溃，放弃
+    assert_eq!(policy.after_crash(fast), None);
+
+    // 跑了很久之后才崩溃的从头计算
+    policy.fast_crashes = 3;
+    assert_eq!(policy.after_crash(slow), Some(Duration::from_secs(5)));
+    assert_eq!(policy.after_crash(fast), Some(Duration::from_secs(5)));
+
+    // 只有崩溃才重启，跑完但有失败的不算崩溃
+    assert_eq!(Exit::from_code(Some(0)), Exit::Finished);
+    assert_eq!(
+        Exit::from_code(Some(error::FAILURES_EXIT_CODE)),
+        Exit::FinishedWithFailures
+    );
+    assert_eq!(Exit::from_code(Some(shutdown::EXIT_CODE)), Exit::Stopped);
+    assert_eq!(Exit::from_code(Some(1)), Exit::Crashed);
+    assert_eq!(Exit::from_code(Some(101)), Exit::Crashed);
+    assert_eq!(Exit::from_code(None), Exit::Crashed);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stop_forwards_signal_to_child() {
+    // 收到 SIGTERM 时以 7 退出的子进程，代替保存进度后退出的采集。
+    let mut child = Command::new("sh")
+        .args(["-c", "trap 'exit 7' TERM; while true; do sleep 0.1; done"])
+        .spawn()
+        .unwrap();
+
+    // 等 trap 设置好之后再要求停止
+    let begin = Instant::now();
+    let status = wait_child(&mut child, || begin.elapsed() > Duration::from_millis(500)).unwrap();
+
+    assert_eq!(status.code(), Some(7));
+}
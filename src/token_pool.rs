@@ -0,0 +1,335 @@
+use std::sync::Mutex;
+
+use crate::util::RateLimit;
+
+/// 每个 token 留给其他工具、采集时不会用掉的额度。
+///
+/// config 里写成分数（`reserve: 500`）或者限额的百分比（`reserve: 10%`）。
+/// 预留额度必须小于 token 的限额，否则重置之后也没有额度可用：百分比在加载 config 时检查，
+/// 分数要等拿到 token 的限额之后用 `check` 检查。
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "ReserveRepr", into = "ReserveRepr")]
+pub enum Reserve {
+    #[default]
+    None,
+    Points(i64),
+    Percent(f64),
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum ReserveRepr {
+    Points(i64),
+    Text(String),
+}
+
+impl TryFrom<ReserveRepr> for Reserve {
+    type Error = String;
+
+    fn try_from(repr: ReserveRepr) -> Result<Self, Self::Error> {
+        match repr {
+            ReserveRepr::Points(points) if points >= 0 => Ok(Reserve::Points(points)),
+            ReserveRepr::Text(text) => match text.trim().strip_suffix('%') {
+                Some(percent) => match percent.trim().parse::<f64>() {
+                    Ok(percent) if (0.0..100.0).contains(&percent) => Ok(Reserve::Percent(percent)),
+                    _ => Err(format!(
+                        "reserve 的百分比需要不小于 0% 并且小于 100%：{text}"
+                    )),
+                },
+                None => text
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|points| *points >= 0)
+                    .map(Reserve::Points)
+                    .ok_or(format!("reserve 需要是非负的分数或者百分比：{text}")),
+            },
+            ReserveRepr::Points(points) => Err(format!("reserve 不能是负数：{points}")),
+        }
+    }
+}
+
+impl From<Reserve> for ReserveRepr {
+    fn from(reserve: Reserve) -> Self {
+        match reserve {
+            Reserve::None => ReserveRepr::Points(0),
+            Reserve::Points(points) => ReserveRepr::Points(points),
+            Reserve::Percent(percent) => ReserveRepr::Text(format!("{percent}%")),
+        }
+    }
+}
+
+impl Reserve {
+    /// 按 token 的限额换算成分数
+    pub fn points(&self, limit: i64) -> i64 {
+        match *self {
+            Reserve::None => 0,
+            Reserve::Points(points) => points,
+            Reserve::Percent(percent) => (limit as f64 * percent / 100.0).ceil() as i64,
+        }
+    }
+
+    /// 预留额度不小于限额时报错
+    pub fn check(&self, limit: i64) -> Result<(), String> {
+        let points = self.points(limit);
+        if points > 0 && points >= limit {
+            return Err(format!(
+                "reserve（{points} 分）不小于 token 的限额 {limit}，重置之后也没有额度可用"
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct TokenSlot {
+    token: String,
+    // 还没有用这个 token 发过请求时不知道额度。
+    rate_limit: Option<RateLimit>,
+    // 本机上同时在用这个 token 的进程数，包括自己。
+    sharers: usize,
+    // 收到过 401，token 已经被撤销或者过期，之后不再使用。
+    revoked: bool,
+}
+
+impl TokenSlot {
+    /// 当前还能用的额度（扣除预留之后），已经过了重置时间的按满额计算，没用过的 token 优先使用。
+    fn budget(&self, now: i64, reserve: Reserve) -> i64 {
+        if self.revoked {
+            return i64::MIN;
+        }
+        match &self.rate_limit {
+            None => i64::MAX,
+            Some(r) if r.reset <= now => r.limit - reserve.points(r.limit),
+            Some(r) => r.remaining - reserve.points(r.limit),
+        }
+    }
+}
+
+/// 多个 PAT 组成的池子，分别记录每个 token 的 `RateLimit`。
+pub struct TokenPool {
+    slots: Mutex<Vec<TokenSlot>>,
+    reserve: Reserve,
+}
+
+impl TokenPool {
+    pub fn new(tokens: Vec<String>, reserve: Reserve) -> Self {
+        Self {
+            slots: Mutex::new(
+                tokens
+                    .into_iter()
+                    .map(|token| TokenSlot {
+                        token,
+                        rate_limit: None,
+                        sharers: 1,
+                        revoked: false,
+                    })
+                    .collect(),
+            ),
+            reserve,
+        }
+    }
+
+    /// 取剩余额度最多的 token，返回它的编号和内容。`now` 是按服务器时钟的 epoch 秒数。
+    ///
+    /// 所有 token 的剩余额度都降到预留额度时返回最早的重置时间（epoch 秒数）。
+    pub fn acquire(&self, now: i64) -> Result<(usize, String), i64> {
+        let slots = self.slots.lock().unwrap();
+
+        let (index, slot) = slots
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, slot)| slot.budget(now, self.reserve))
+            .expect("token 池不能为空");
+
+        if slot.budget(now, self.reserve) > 0 {
+            return Ok((index, slot.token.clone()));
+        }
+
+        let earliest_reset = slots
+            .iter()
+            .filter(|slot| !slot.revoked)
+            .filter_map(|slot| slot.rate_limit.as_ref().map(|r| r.reset))
+            .min()
+            .unwrap_or(now);
+
+        Err(earliest_reset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    /// 标记 token 已经失效，返回是否还有其他可用的 token。
+    pub fn revoke(&self, index: usize) -> bool {
+        if let Some(slot) = self.slots.lock().unwrap().get_mut(index) {
+            slot.revoked = true;
+        }
+        self.usable() > 0
+    }
+
+    /// 没有失效的 token 数
+    pub fn usable(&self) -> usize {
+        let slots = self.slots.lock().unwrap();
+        slots.iter().filter(|slot| !slot.revoked).count()
+    }
+
+    pub fn reserve(&self) -> Reserve {
+        self.reserve
+    }
+
+    pub fn update(&self, index: usize, rate_limit: RateLimit) {
+        if let Some(slot) = self.slots.lock().unwrap().get_mut(index) {
+            slot.rate_limit = Some(rate_limit);
+        }
+    }
+
+    /// 每个 token 的内容和当前记录的额度，按编号排列。
+    pub fn snapshot(&self) -> Vec<(String, Option<RateLimit>)> {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .iter()
+            .map(|slot| (slot.token.clone(), slot.rate_limit))
+            .collect()
+    }
+
+    /// 用其他进程共享过来的额度覆盖本地记录，`sharers` 是同时在用这个 token 的进程数。
+    pub fn merge_shared(&self, index: usize, rate_limit: Option<RateLimit>, sharers: usize) {
+        if let Some(slot) = self.slots.lock().unwrap().get_mut(index) {
+            slot.rate_limit = rate_limit.or(slot.rate_limit);
+            slot.sharers = sharers.max(1);
+        }
+    }
+
+    /// 是否还有 token 有剩余额度
+    pub fn has_available(&self, now: i64) -> bool {
+        self.acquire(now).is_ok()
+    }
+
+    /// 所有 token 合计每秒可以用掉的分数，用作 governor 令牌桶的补充速度。
+    ///
+    /// 每个 token 扣除预留之后的剩余额度平摊到距离重置的时间里；
+    /// 已经重置或者还没用过的 token 按满额一小时计算。多个进程共用的 token 由这些进程平分。
+    pub fn refill_per_sec(&self, now: i64) -> f64 {
+        let slots = self.slots.lock().unwrap();
+
+        slots
+            .iter()
+            .filter(|slot| !slot.revoked)
+            .map(|slot| {
+                let per_sec = match &slot.rate_limit {
+                    None => {
+                        let limit = RateLimit::default().limit;
+                        (limit - self.reserve.points(limit)) as f64 / 3600.0
+                    }
+                    Some(r) if r.reset <= now => {
+                        (r.limit - self.reserve.points(r.limit)) as f64 / 3600.0
+                    }
+                    Some(r) => {
+                        slot.budget(now, self.reserve).max(0) as f64 / (r.reset - now) as f64
+                    }
+                };
+                per_sec / slot.sharers as f64
+            })
+            .sum()
+    }
+}
+
+#[test]
+fn test_acquire_prefers_most_remaining() {
+    let now = chrono::Utc::now().timestamp();
+    let pool = TokenPool::new(vec!["a".into(), "b".into(), "c".into()], Reserve::None);
+
+    pool.update(0, RateLimit::new(5000, 100, 4900, now + 600));
+    pool.update(1, RateLimit::new(5000, 3000, 2000, now + 600));
+    // 还没用过的 token 优先
+    assert_eq!(pool.acquire(now), Ok((2, "c".to_string())));
+
+    pool.update(2, RateLimit::new(5000, 0, 5000, now + 600));
+    assert_eq!(pool.acquire(now), Ok((1, "b".to_string())));
+}
+
+#[test]
+fn test_acquire_waits_only_when_all_exhausted() {
+    let now = chrono::Utc::now().timestamp();
+    let pool = TokenPool::new(vec!["a".into(), "b".into()], Reserve::None);
+
+    pool.update(0, RateLimit::new(5000, 0, 5000, now + 600));
+    assert!(pool.has_available(now));
+
+    pool.update(1, RateLimit::new(5000, 0, 5000, now + 120));
+    assert_eq!(pool.acquire(now), Err(now + 120));
+
+    // 过了重置时间的 token 按满额重新可用
+    pool.update(1, RateLimit::new(5000, 0, 5000, now - 1));
+    assert_eq!(pool.acquire(now), Ok((1, "b".to_string())));
+    // 按服务器时钟判断，本机时钟慢的时候同样可用
+    pool.update(1, RateLimit::new(5000, 0, 5000, now + 30));
+    assert!(pool.has_available(now + 60));
+}
+
+#[test]
+fn test_refill_spreads_remaining_until_reset() {
+    let now = chrono::Utc::now().timestamp();
+    let pool = TokenPool::new(vec!["a".into(), "b".into()], Reserve::None);
+
+    // 还没用过的 token 按每小时 5000 分
+    assert!((pool.refill_per_sec(now) - 2.0 * 5000.0 / 3600.0).abs() < 1e-9);
+
+    pool.update(0, RateLimit::new(5000, 1200, 3800, now + 600));
+    pool.update(1, RateLimit::new(5000, 0, 5000, now + 60));
+    assert!((pool.refill_per_sec(now) - 2.0).abs() < 1e-9);
+
+    // 第二个 token 重置之后按满额计算
+    assert!((pool.refill_per_sec(now + 60) - (1200.0 / 540.0 + 5000.0 / 3600.0)).abs() < 1e-9);
+
+    // 另一个进程也在用第一个 token 时平分
+    pool.merge_shared(0, None, 2);
+    assert!((pool.refill_per_sec(now) - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_reserve_is_never_used() {
+    let now = chrono::Utc::now().timestamp();
+
+    let reserve: Reserve = serde_yaml::from_str("500").unwrap();
+    assert_eq!(reserve, Reserve::Points(500));
+    let reserve: Reserve = serde_yaml::from_str("10%").unwrap();
+    assert_eq!(reserve, Reserve::Percent(10.0));
+    assert_eq!(reserve.points(5000), 500);
+    assert!(serde_yaml::from_str::<Reserve>("150%").is_err());
+    assert!(serde_yaml::from_str::<Reserve>("100%").is_err());
+    assert!(Reserve::Points(5000).check(5000).is_err());
+    assert!(Reserve::Points(4999).check(5000).is_ok());
+    assert!(Reserve::Percent(99.99).check(5000).is_err());
+    assert!(Reserve::None.check(5000).is_ok());
+    assert!(serde_yaml::from_str::<Reserve>("-1").is_err());
+
+    let pool = TokenPool::new(vec!["a".into()], reserve);
+    pool.update(0, RateLimit::new(5000, 501, 4499, now + 600));
+    assert!(pool.has_available(now));
+
+    // 剩余额度降到预留额度就暂停，直到重置
+    pool.update(0, RateLimit::new(5000, 500, 4500, now + 600));
+    assert_eq!(pool.acquire(now), Err(now + 600));
+    assert_eq!(pool.refill_per_sec(now), 0.0);
+    assert!(pool.has_available(now + 600));
+    assert!((pool.refill_per_sec(now + 600) - 4500.0 / 3600.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_revoked_token_is_never_picked() {
+    let now = chrono::Utc::now().timestamp();
+    let pool = TokenPool::new(vec!["a".into(), "b".into()], Reserve::None);
+
+    pool.update(1, RateLimit::new(5000, 10, 4990, now + 600));
+    assert_eq!(pool.acquire(now), Ok((0, "a".to_string())));
+
+    // 还没用过的 token 失效之后，额度少的也要用
+    assert!(pool.revoke(0));
+    assert_eq!(pool.usable(), 1);
+    assert_eq!(pool.acquire(now), Ok((1, "b".to_string())));
+    assert!((pool.refill_per_sec(now) - 10.0 / 600.0).abs() < 1e-9);
+
+    assert!(!pool.revoke(1));
+    assert_eq!(pool.usable(), 0);
+}
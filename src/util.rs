@@ -1,61 +1,132 @@
 use anyhow::{Context, Result};
-use chrono::{DateTime, TimeZone};
-use rand::Rng;
 use reqwest::header::HeaderMap;
-use std::time::Duration;
-use std::{fs, thread};
-use std::{io::Write, path::Path};
-
-#[derive(Debug, Clone, Copy)]
-pub enum TaskType {
-    Discussions,
-    ClosedIssues,
-    PRCommits,
+use std::fs;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+// 变体、名字和采集顺序见 `query::task_registry!`
+crate::query::task_registry!(define_task_type!());
+
+impl TaskType {
+    /// 分页文件中 `repository` 下面对应的连接字段名
+    pub fn connection_name(&self) -> &'static str {
+        crate::query::for_task_type!(*self, T => <T as crate::query::PaginatedTask>::CONNECTION)
+    }
+}
+
+/// 读取仓库列表，每行一个 `<owner>/<repo>`，`#` 开头的行会被忽略。
+pub fn read_repo_list(path: &Path) -> Result<Vec<(String, String)>> {
+    let file = fs::File::open(path).context(format!("没有找到 {}", path.display()))?;
+
+    Ok(io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with('#') {
+                return None;
+            }
+            let mut it = line.split('/');
+            let repo_owner = it.next()?.to_string();
+            let repo_name = it.next()?.to_string();
+            Some((repo_owner, repo_name))
+        })
+        .collect())
 }
 
-impl std::fmt::Display for TaskType {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            TaskType::Discussions => write!(f, "discussion"),
-            TaskType::ClosedIssues => write!(f, "issue"),
-            TaskType::PRCommits => write!(f, "pull_request"),
+/// 每个仓库每个任务的输出路径：`<root>/<owner>_<repo>/<task>`
+pub fn task_dir(root: &Path, owner: &str, repo: &str, task_type: TaskType) -> std::path::PathBuf {
+    root.join(format!("{}_{}", owner, repo))
+        .join(task_type.to_string())
+}
+
+/// 列出任务目录下的所有分页文件，返回相对于任务目录、以 `/` 分隔的路径。
+///
+/// 旧布局里 cursor 中的 `/` 会让文件落在子目录里，所以这里会递归查找。
+pub fn list_pages(task_path: &Path) -> Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, pages: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir).context(format!("{} 文件夹不存在", dir.display()))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let relative = format!("{prefix}{name}");
+
+            if path.is_dir() {
+                walk(&path, &format!("{relative}/"), pages)?;
+            } else if path.extension().is_some_and(|e| e == "json") {
+                pages.push(relative);
+            }
         }
+        Ok(())
     }
+
+    let mut pages = Vec::new();
+    walk(task_path, "", &mut pages)?;
+
+    // read_dir 本身不保证顺序，这里按文件名排一次。
+    pages.sort();
+
+    Ok(pages)
+}
+
+/// 临时文件的后缀，`list_pages` 只认 `.json`，所以写了一半的临时文件不会被当成分页。
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// 先写到同目录下的临时文件、fsync 之后再改名，进程在任何时候被杀掉都不会留下写了一半的文件。
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(TEMP_SUFFIX);
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, path)?;
+
+    // 改名本身也要落盘，Windows 上不能打开目录，忽略即可。
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
 }
 
 pub fn dump_output(
+    root: &Path,
     parsed_json: &str,
     owner: &str,
     repo: &str,
     task_type: TaskType,
     id: &Option<String>,
     window_number: i32,
-) -> Result<()> {
-    let full_path = Path::new("output")
-        .join(format!("{}_{}", owner, repo))
-        .join(task_type.to_string())
-        .join(format!(
-            "{window_number:03}_{}.json",
-            id.clone().unwrap_or("first_cursor".to_string())
-        ));
+) -> Result<String> {
+    let file_name = crate::layout::page_file_name(window_number, id);
+    let full_path = task_dir(root, owner, repo, task_type).join(&file_name);
 
     if !full_path.exists() {
         fs::create_dir_all(full_path.parent().unwrap())
             .context(format!("{full_path:?} 路径创建出现问题"))?;
     }
 
-    fs::File::create(&full_path)?.write_all(parsed_json.as_bytes())?;
+    write_atomic(&full_path, parsed_json.as_bytes())
+        .context(format!("{} 写入失败", full_path.display()))?;
 
     log::info!("成功导出文件： {fp}", fp = full_path.to_string_lossy());
 
-    Ok(())
+    Ok(file_name)
 }
 
 /// 参考 https://docs.github.com/en/graphql/overview/rate-limits-and-node-limits-for-the-graphql-api
 /// If you exceed your primary rate limit, the response status will still be 200, but you will receive
 /// an error message, and the value of the x-ratelimit-remaining header will be 0. You should not retry
 ///  your request until after the time specified by the x-ratelimit-reset header.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub struct RateLimit {
     pub limit: i64,
     pub remaining: i64,
@@ -75,7 +146,7 @@ impl Default for RateLimit {
 }
 
 impl RateLimit {
-    fn new(limit: i64, remaining: i64, used: i64, reset: i64) -> Self {
+    pub fn new(limit: i64, remaining: i64, used: i64, reset: i64) -> Self {
         Self {
             limit,
             remaining,
@@ -106,41 +177,3 @@ impl TryFrom<&HeaderMap> for RateLimit {
         ))
     }
 }
-
-pub fn check_limit_and_block(
-    RateLimit {
-        limit,
-        remaining,
-        used,
-        reset,
-    }: RateLimit,
-) {
-    log::info!(
-        "limit: ({used}/{limit}) remaining: {remaining} 剩余重置时间： {reset_time} s",
-        reset_time = calc_github_reset_diff(reset),
-    );
-
-    // TODO 我感觉请求间隔时间放在这个函数其实已经不合适了，需要调整到 graphql_client_ext 文件中去更好。
-    if remaining < 3 {
-        // 实际上这种情况非常难触及到，因为每次请求的查询量很大，门槛不在 5000 次的限制。
-        // 先设置睡眠五分钟，一般会在请求函数中等待更久。
-        thread::sleep(Duration::from_secs(300));
-    } else {
-        let sleep_millis = if used % 3 == 0 {
-            // 每 3 次，就设置一个稍微长的休眠。
-            rand::thread_rng().gen_range(3_000..=9_000)
-        } else {
-            rand::thread_rng().gen_range(1_000..=3_000)
-        };
-
-        log::info!("开始休眠随机间隔 {sleep_millis}ms");
-        thread::sleep(Duration::from_millis(sleep_millis));
-    }
-}
-
-fn calc_github_reset_diff(end_timestamp: i64) -> i64 {
-    // github 给的时间戳是加州西 7 区的时间，所以需要转换一下。
-    let time_zone = chrono::FixedOffset::west_opt(7 * 3600).unwrap();
-    let now_timestamp = chrono::Utc::now().with_timezone(&time_zone).timestamp();
-    (end_timestamp - now_timestamp).max(0)
-}
@@ -0,0 +1,129 @@
+// 每个仓库每类任务一份的自适应窗口大小，记录在元数据里，续爬时从上次能用的大小开始。
+//
+// 502/504 时 `post_graphql_blocking` 会缩小本次请求的窗口，这里记住缩小后的大小；
+// 返回得很慢的请求说明已经接近 github 的超时，也提前缩小；
+// 连续几次请求都很快返回时再逐步放大，直到 `MAX_WINDOW`。
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::metrics::{self, Metric};
+
+// 虽然 last 或者 first 只能填写 1-100，但是一次请求的 node 上限是 500,000。
+pub const MAX_WINDOW: i64 = 100;
+
+/// 连续这么多次快速返回之后放大一次
+const GROW_AFTER: u32 = 3;
+const GROW_FACTOR: f64 = 1.5;
+
+/// 比这个快的请求算作快速返回
+const FAST_REQUEST: Duration = Duration::from_secs(5);
+/// github 的查询超时是 10 秒，比这个慢的请求下次主动缩小窗口。
+const SLOW_REQUEST: Duration = Duration::from_secs(8);
+const SHRINK_FACTOR: f64 = 2.0 / 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveWindow {
+    /// 下一次请求使用的窗口大小
+    pub size: i64,
+    /// 当前大小下已经连续快速返回的次数
+    #[serde(default)]
+    pub fast_streak: u32,
+}
+
+impl Default for AdaptiveWindow {
+    fn default() -> Self {
+        Self {
+            size: MAX_WINDOW,
+            fast_streak: 0,
+        }
+    }
+}
+
+impl AdaptiveWindow {
+    /// 重试全部失败时，记住重试过程中缩小到的大小。
+    pub fn gave_up(&mut self, shrunk: i64) {
+        if shrunk < self.size {
+            self.size = shrunk.max(1);
+            self.fast_streak = 0;
+        }
+    }
+
+    /// 根据一次成功请求实际使用的窗口和耗时调整下一次的大小。
+    pub fn observe(&mut self, used: i64, elapsed: Duration) {
+        if used < self.size {
+            // 重试时被缩小过，说明原来的大小会超时。
+            self.size = used.max(1);
+            self.fast_streak = 0;
+        } else if elapsed >= SLOW_REQUEST {
+            self.size = ((self.size as f64 * SHRINK_FACTOR) as i64).max(1);
+            self.fast_streak = 0;
+            metrics::inc(Metric::WindowShrinks, &[("cause", "slow")], 1.0);
+        } else if elapsed < FAST_REQUEST {
+            self.fast_streak += 1;
+            if self.fast_streak >= GROW_AFTER && self.size < MAX_WINDOW {
+                self.size = ((self.size as f64 * GROW_FACTOR).ceil() as i64).min(MAX_WINDOW);
+                self.fast_streak = 0;
+            }
+        } else {
+            self.fast_streak = 0;
+        }
+    }
+}
+
+#[test]
+fn test_shrink_and_grow_back() {
+    let fast = Duration::from_secs(1);
+    let mut window = AdaptiveWindow::default();
+
+    // 502 之后重试成功时用的是 66
+    window.observe(66, fast);
+    assert_eq!(window.size, 66);
+
+    window.observe(66, Duration::from_secs(9));
+    assert_eq!(window.size, 44);
+
+    // 连续三次快速返回才放大
+    window.observe(44, fast);
+    window.observe(44, fast);
+    assert_eq!(window.size, 44);
+    window.observe(44, fast);
+    assert_eq!(window.size, 66);
+
+    // 中间夹一个不快不慢的请求会重新计数
+    window.observe(66, fast);
+    window.observe(66, Duration::from_secs(6));
+    window.observe(66, fast);
+    window.observe(66, fast);
+    assert_eq!(window.size, 66);
+    window.observe(66, fast);
+    assert_eq!(window.size, 99);
+
+    for _ in 0..6 {
+        window.observe(window.size, fast);
+    }
+    assert_eq!(window.size, MAX_WINDOW);
+}
+
+#[test]
+fn test_keep_shrunk_window_after_giving_up() {
+    let mut window = AdaptiveWindow {
+        size: 66,
+        fast_streak: 2,
+    };
+
+    // 502 之后缩小到 44 仍然失败
+    window.gave_up(44);
+    assert_eq!(
+        window,
+        AdaptiveWindow {
+            size: 44,
+            fast_streak: 0
+        }
+    );
+
+    // 没有缩小过的失败不影响窗口
+    window.gave_up(44);
+    window.gave_up(100);
+    assert_eq!(window.size, 44);
+}